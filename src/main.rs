@@ -1,11 +1,27 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::error::Error;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read};
+use std::net::{IpAddr, SocketAddr};
 use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::de::{
+    DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor,
+};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use ureq::unversioned::resolver::{DefaultResolver, ResolvedSocketAddrs, Resolver};
+use ureq::unversioned::transport::{DefaultConnector, NextTimeout};
 
 // カスタムエラー型
 #[derive(Debug)]
@@ -15,6 +31,26 @@ pub enum GeminiError {
     ParseError(String),
     ApiError(String),
     FileError(String),
+    // 2xx 以外のHTTPステータス
+    // status は "RESOURCE_EXHAUSTED" や "INVALID_ARGUMENT" など。details はクォータの情報などを持つ
+    // headers はレスポンスヘッダ (Retry-After や X-RateLimit-* など)。ヘッダ名は小文字
+    HttpStatus {
+        code: u16,
+        message: String,
+        status: Option<String>,
+        details: Vec<Value>,
+        headers: HashMap<String, String>,
+    },
+    // run_agent が max_turns 以内に終わらなかった。それまでの会話履歴を持つ
+    MaxTurnsExceeded(Vec<Content>),
+    // ストリーミングで一定時間データが届かなかった
+    Timeout(String),
+    // APIキーが拒否された (401 / 403)
+    AuthError(String),
+    // 全ての候補が SAFETY や RECITATION で止められ、テキストが無い
+    Blocked(String),
+    // 指定したモデルが存在しない (モデルのURLへのリクエストが 404 になった)
+    ModelNotFound(String),
 }
 
 impl fmt::Display for GeminiError {
@@ -25,6 +61,57 @@ impl fmt::Display for GeminiError {
             GeminiError::ParseError(msg) => write!(f, "Parse error: {}", msg),
             GeminiError::ApiError(msg) => write!(f, "API error: {}", msg),
             GeminiError::FileError(msg) => write!(f, "File error: {}", msg),
+            GeminiError::HttpStatus { code, message, status: Some(status), .. } => {
+                write!(f, "HTTP {} {}: {}", code, status, message)
+            }
+            GeminiError::HttpStatus { code, message, .. } => write!(f, "HTTP {}: {}", code, message),
+            GeminiError::MaxTurnsExceeded(transcript) => write!(
+                f,
+                "Agent did not finish within max turns ({} contents in transcript)",
+                transcript.len()
+            ),
+            GeminiError::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            GeminiError::AuthError(msg) => write!(f, "Authentication error: {}", msg),
+            GeminiError::Blocked(msg) => write!(f, "Blocked: {}", msg),
+            GeminiError::ModelNotFound(model) => write!(f, "Model '{}' not found", model),
+        }
+    }
+}
+
+impl GeminiError {
+    // API が返したエラーの status ("RESOURCE_EXHAUSTED" など)。クォータ不足と引数の間違いを区別できる
+    pub fn status(&self) -> Option<&str> {
+        match self {
+            GeminiError::HttpStatus { status, .. } => status.as_deref(),
+            _ => None,
+        }
+    }
+
+    // 時間をおいて送り直せば成功する可能性があるエラー
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            GeminiError::NetworkError(_) | GeminiError::Timeout(_) => true,
+            GeminiError::HttpStatus { code, .. } => matches!(code, 408 | 429 | 500 | 502 | 503 | 504),
+            _ => false,
+        }
+    }
+
+    // APIキーが拒否された。401 / 403 のほか、不正なキーは 400 で details の reason が API_KEY_INVALID になる
+    fn is_invalid_api_key(&self) -> bool {
+        match self {
+            GeminiError::HttpStatus { code: 401 | 403, .. } => true,
+            GeminiError::HttpStatus { code: 400, details, .. } => details.iter()
+                .any(|detail| detail.get("reason").and_then(Value::as_str) == Some("API_KEY_INVALID")),
+            _ => false,
+        }
+    }
+
+    // 指定したモデルが存在しない
+    fn is_model_unavailable(&self) -> bool {
+        match self {
+            GeminiError::ModelNotFound(_) => true,
+            GeminiError::HttpStatus { code, .. } => *code == 404,
+            _ => false,
         }
     }
 }
@@ -39,10 +126,63 @@ pub struct FunctionDeclaration {
     pub parameters: FunctionParameters,
 }
 
+impl FunctionDeclaration {
+    // APIに送る前にスキーマの型を確認する。不正な型は 400 になりエラー内容がわかりにくい
+    pub fn validate(&self) -> Result<(), GeminiError> {
+        if self.parameters.param_type != JsonSchemaType::Object {
+            return Err(GeminiError::ApiError(format!(
+                "Parameters of function '{}' must have type \"object\", got \"{}\"",
+                self.name, self.parameters.param_type.as_str()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// 全てのツールを通して関数の名前が重複していないか確認する
+fn check_duplicate_functions<'a>(
+    declarations: impl Iterator<Item = &'a FunctionDeclaration>,
+) -> Result<(), GeminiError> {
+    let mut names = std::collections::HashSet::new();
+    for declaration in declarations {
+        if !names.insert(declaration.name.as_str()) {
+            return Err(GeminiError::ApiError(format!(
+                "Function '{}' is declared more than once", declaration.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// スキーマの型。API には小文字の文字列で送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonSchemaType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array,
+    Object,
+}
+
+impl JsonSchemaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JsonSchemaType::String => "string",
+            JsonSchemaType::Number => "number",
+            JsonSchemaType::Integer => "integer",
+            JsonSchemaType::Boolean => "boolean",
+            JsonSchemaType::Array => "array",
+            JsonSchemaType::Object => "object",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionParameters {
     #[serde(rename = "type")]
-    pub param_type: String,
+    pub param_type: JsonSchemaType,
     pub properties: HashMap<String, PropertySchema>,
     pub required: Vec<String>,
 }
@@ -50,15 +190,408 @@ pub struct FunctionParameters {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PropertySchema {
     #[serde(rename = "type")]
-    pub property_type: String,
+    pub property_type: JsonSchemaType,
+    // 空の時は送らない
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub description: String,
     #[serde(rename = "enum", skip_serializing_if = "Option::is_none")]
     pub enum_values: Option<Vec<String>>,
+    // "date-time" や "int32" などの形式
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    // array の要素のスキーマ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<PropertySchema>>,
+    // object のフィールドのスキーマと、省略できないフィールド
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub properties: Option<HashMap<String, PropertySchema>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required: Option<Vec<String>>,
+}
+
+impl PropertySchema {
+    fn new(property_type: JsonSchemaType, description: &str) -> Self {
+        PropertySchema {
+            property_type,
+            description: description.to_string(),
+            enum_values: None,
+            format: None,
+            items: None,
+            properties: None,
+            required: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: &str) -> Self {
+        self.format = Some(format.to_string());
+        self
+    }
+
+    pub fn string(description: &str) -> Self {
+        Self::new(JsonSchemaType::String, description)
+    }
+
+    pub fn number(description: &str) -> Self {
+        Self::new(JsonSchemaType::Number, description)
+    }
+
+    pub fn boolean(description: &str) -> Self {
+        Self::new(JsonSchemaType::Boolean, description)
+    }
+
+    // 取りうる値を列挙した文字列
+    pub fn enumerated(description: &str, values: &[&str]) -> Self {
+        PropertySchema {
+            enum_values: Some(values.iter().map(|v| v.to_string()).collect()),
+            ..Self::new(JsonSchemaType::String, description)
+        }
+    }
+}
+
+// T をデシリアライズする時に serde が求める型をたどって、パラメータのスキーマを作る
+// 値は作らないので T: Default は要らない。Option のフィールドは省略可能、それ以外は必須になる
+// 使えるのは数値・文字列・bool・Option・Vec などの列・構造体・値を持たない enum (文字列の列挙) で、
+// HashMap やタプル、値を持つ enum、#[serde(flatten)]、serde_json::Value などはエラーになる
+pub fn schema_for<T: DeserializeOwned>() -> Result<FunctionParameters, GeminiError> {
+    let mut traced = TracedSchema::default();
+    T::deserialize(SchemaTracer { out: &mut traced, depth: 0 })
+        .map_err(|e| GeminiError::ParseError(format!("Cannot derive schema: {}", e)))?;
+    match traced.schema {
+        Some(PropertySchema {
+            property_type: JsonSchemaType::Object,
+            properties: Some(properties),
+            required,
+            ..
+        }) if !traced.optional => Ok(FunctionParameters {
+            param_type: JsonSchemaType::Object,
+            properties,
+            required: required.unwrap_or_default(),
+        }),
+        _ => Err(GeminiError::ParseError(
+            "Schema can only be derived from a struct".to_string(),
+        )),
+    }
+}
+
+// 再帰的な型で止まらなくなるのを防ぐ
+const MAX_SCHEMA_DEPTH: usize = 16;
+
+// schema_for で一つの値についてわかったこと
+#[derive(Default)]
+struct TracedSchema {
+    schema: Option<PropertySchema>,
+    // Option だった
+    optional: bool,
+}
+
+#[derive(Debug)]
+struct SchemaError(String);
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for SchemaError {}
+
+impl serde::de::Error for SchemaError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        SchemaError(msg.to_string())
+    }
+}
+
+// 値の代わりにスキーマを out に書き込む Deserializer
+// Visitor には仮の値を渡し、T の値は捨てる
+struct SchemaTracer<'a> {
+    out: &'a mut TracedSchema,
+    depth: usize,
+}
+
+impl SchemaTracer<'_> {
+    fn set(self, property_type: JsonSchemaType) {
+        self.out.schema = Some(PropertySchema::new(property_type, ""));
+    }
+
+    fn nested(&self) -> Result<usize, SchemaError> {
+        if self.depth >= MAX_SCHEMA_DEPTH {
+            return Err(SchemaError("type is nested too deeply (recursive types are not supported)".to_string()));
+        }
+        Ok(self.depth + 1)
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for SchemaTracer<'_> {
+    type Error = SchemaError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, SchemaError> {
+        Err(SchemaError("unsupported type (maps, tuples, bytes and untagged values have no fixed schema)".to_string()))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Boolean);
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_i8(1)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_i16(1)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_i32(1)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_i64(1)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_u8(1)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_u16(1)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_u32(1)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Integer);
+        visitor.visit_u64(1)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Number);
+        visitor.visit_f32(0.0)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::Number);
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::String);
+        visitor.visit_char('a')
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::String);
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.set(JsonSchemaType::String);
+        visitor.visit_string(String::new())
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        self.out.optional = true;
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SchemaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        let mut items = TracedSchema::default();
+        let value = visitor.visit_seq(SeqTracer { items: &mut items, depth: self.nested()?, done: false })?;
+        let items = items.schema
+            .ok_or_else(|| SchemaError("array elements have no schema".to_string()))?;
+        self.out.schema = Some(PropertySchema {
+            items: Some(Box::new(items)),
+            ..PropertySchema::new(JsonSchemaType::Array, "")
+        });
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SchemaError> {
+        let mut properties = HashMap::new();
+        let mut required = Vec::new();
+        let value = visitor.visit_map(StructTracer {
+            fields,
+            index: 0,
+            properties: &mut properties,
+            required: &mut required,
+            depth: self.nested()?,
+        })?;
+        self.out.schema = Some(PropertySchema {
+            properties: Some(properties),
+            required: Some(required),
+            ..PropertySchema::new(JsonSchemaType::Object, "")
+        });
+        Ok(value)
+    }
+
+    // 値を持たない enum はバリアント名の列挙になる
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SchemaError> {
+        let first = *variants.first()
+            .ok_or_else(|| SchemaError(format!("enum {} has no variants", name)))?;
+        self.out.schema = Some(PropertySchema::enumerated("", variants));
+        visitor.visit_enum(UnitVariantTracer { name, variant: first })
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct tuple tuple_struct map identifier ignored_any
+    }
+}
+
+// 列の要素を一つだけ渡して、要素の型をたどる
+struct SeqTracer<'a> {
+    items: &'a mut TracedSchema,
+    depth: usize,
+    done: bool,
+}
+
+impl<'de> SeqAccess<'de> for SeqTracer<'_> {
+    type Error = SchemaError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, SchemaError> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        seed.deserialize(SchemaTracer { out: &mut *self.items, depth: self.depth }).map(Some)
+    }
+}
+
+// 構造体の全てのフィールドを順に渡して、それぞれの型をたどる
+struct StructTracer<'a> {
+    fields: &'static [&'static str],
+    index: usize,
+    properties: &'a mut HashMap<String, PropertySchema>,
+    required: &'a mut Vec<String>,
+    depth: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl<'de> MapAccess<'de> for StructTracer<'_> {
+    type Error = SchemaError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, SchemaError> {
+        match self.fields.get(self.index) {
+            Some(field) => seed.deserialize(field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, SchemaError> {
+        let field = self.fields[self.index];
+        self.index += 1;
+
+        let mut traced = TracedSchema::default();
+        let value = seed.deserialize(SchemaTracer { out: &mut traced, depth: self.depth })
+            .map_err(|e| SchemaError(format!("field '{}': {}", field, e)))?;
+        let schema = traced.schema
+            .ok_or_else(|| SchemaError(format!("field '{}' has no schema", field)))?;
+        if !traced.optional {
+            self.required.push(field.to_string());
+        }
+        self.properties.insert(field.to_string(), schema);
+        Ok(value)
+    }
+}
+
+// enum の最初のバリアントを選ぶ。値を持つバリアントだった場合はエラーにする
+struct UnitVariantTracer {
+    name: &'static str,
+    variant: &'static str,
+}
+
+impl UnitVariantTracer {
+    fn data_error(&self) -> SchemaError {
+        SchemaError(format!("enum {} has variants with data, which have no fixed schema", self.name))
+    }
+}
+
+impl<'de> EnumAccess<'de> for UnitVariantTracer {
+    type Error = SchemaError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), SchemaError> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for UnitVariantTracer {
+    type Error = SchemaError;
+
+    fn unit_variant(self) -> Result<(), SchemaError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, _seed: T) -> Result<T::Value, SchemaError> {
+        Err(self.data_error())
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, _visitor: V) -> Result<V::Value, SchemaError> {
+        Err(self.data_error())
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, SchemaError> {
+        Err(self.data_error())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tool {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub function_declarations: Vec<FunctionDeclaration>,
+    // モデルに Python のコードを実行させるツール。中身は空のオブジェクト
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_execution: Option<CodeExecution>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CodeExecution {}
+
+// モデルが生成して実行したコード
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutableCode {
+    // 今のところ "PYTHON" のみ
+    pub language: String,
+    pub code: String,
+}
+
+// コードの実行結果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CodeExecutionResult {
+    // "OUTCOME_OK", "OUTCOME_FAILED", "OUTCOME_DEADLINE_EXCEEDED" など
+    pub outcome: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,60 +600,707 @@ pub struct FunctionCall {
     pub args: serde_json::Value,
 }
 
+impl FunctionCall {
+    // 引数をユーザー定義の構造体にデシリアライズする
+    pub fn args_as<T: DeserializeOwned>(&self) -> Result<T, GeminiError> {
+        serde_json::from_value(self.args.clone())
+            .map_err(|e| GeminiError::ParseError(format!("Invalid arguments for {}: {}", self.name, e)))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FunctionResponse {
     pub name: String,
     pub response: serde_json::Value,
+    // 時間のかかるツール向け。true ならこの関数の結果がまだ続くことをモデルに伝える
+    #[serde(rename = "willContinue", default, skip_serializing_if = "Option::is_none")]
+    pub will_continue: Option<bool>,
+    // 結果を受け取ったモデルの振る舞い (SILENT, WHEN_IDLE, INTERRUPT)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduling: Option<String>,
+}
+
+impl FunctionResponse {
+    pub fn new(name: &str, response: serde_json::Value) -> Self {
+        FunctionResponse {
+            name: name.to_string(),
+            response,
+            will_continue: None,
+            scheduling: None,
+        }
+    }
+
+    // {"result": "success", ...} の形の結果。value がオブジェクトならそのフィールドを並べ、
+    // それ以外は "output" に入れる
+    pub fn success(name: &str, value: serde_json::Value) -> Self {
+        let response = match value {
+            serde_json::Value::Object(mut fields) => {
+                fields.insert("result".to_string(), "success".into());
+                serde_json::Value::Object(fields)
+            }
+            other => serde_json::json!({
+                "result": "success",
+                "output": other,
+            }),
+        };
+        Self::new(name, response)
+    }
+
+    // {"result": "failure", "error": message} の形の結果
+    pub fn error(name: &str, message: &str) -> Self {
+        Self::new(name, serde_json::json!({
+            "result": "failure",
+            "error": message,
+        }))
+    }
+
+    pub fn with_will_continue(mut self, will_continue: bool) -> Self {
+        self.will_continue = Some(will_continue);
+        self
+    }
+
+    pub fn with_scheduling(mut self, scheduling: &str) -> Self {
+        self.scheduling = Some(scheduling.to_string());
+        self
+    }
 }
 
 // リクエスト用の構造体
-#[derive(Debug, Clone, Serialize)]
+// 保存した会話履歴を読み込んで run_agent_continue に渡せるよう、Deserialize もできる
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Content {
     pub role: String,
     pub parts: Vec<Part>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+// 会話履歴
+pub type Conversation = Vec<Content>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Model,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Model => "model",
+        }
+    }
+}
+
+impl Content {
+    pub fn builder(role: Role) -> ContentBuilder {
+        ContentBuilder {
+            role,
+            parts: vec![],
+        }
+    }
+
+    // 関数を呼び出すモデルのターン (thoughtSignature は付かない)
+    pub fn model_function_call(call: FunctionCall) -> Content {
+        Content {
+            role: Role::Model.as_str().to_string(),
+            parts: vec![Part::FunctionCall {
+                function_call: call,
+                thought_signature: None,
+            }],
+        }
+    }
+
+    // 関数の結果を返すユーザーのターン
+    pub fn function_result(name: &str, result: Value) -> Content {
+        Content {
+            role: Role::User.as_str().to_string(),
+            parts: vec![Part::FunctionResponse {
+                function_response: FunctionResponse::new(name, result),
+            }],
+        }
+    }
+}
+
+// テキストと画像が混在するターンを組み立てる
+pub struct ContentBuilder {
+    role: Role,
+    parts: Vec<Part>,
+}
+
+impl ContentBuilder {
+    pub fn text(mut self, text: &str) -> Self {
+        self.parts.push(Part::Text {
+            text: text.to_string(),
+        });
+        self
+    }
+
+    pub fn image(mut self, mime_type: &str, bytes: &[u8]) -> Self {
+        self.parts.push(Part::InlineData {
+            inline_data: InlineData::from_bytes(mime_type, bytes),
+        });
+        self
+    }
+
+    pub fn audio(mut self, mime_type: &str, bytes: &[u8]) -> Self {
+        self.parts.push(Part::InlineData {
+            inline_data: InlineData::from_bytes(mime_type, bytes),
+        });
+        self
+    }
+
+    pub fn build(self) -> Content {
+        Content {
+            role: self.role.as_str().to_string(),
+            parts: self.parts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InlineData {
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+    // base64エンコードされたデータ
+    pub data: String,
+}
+
+impl InlineData {
+    pub fn from_bytes(mime_type: &str, bytes: &[u8]) -> Self {
+        InlineData {
+            mime_type: mime_type.to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SystemInstruction {
+    // v1beta では省略する。None の時は v1 の場合だけ "system" を補って送る
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
     pub parts: Vec<Part>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl SystemInstruction {
+    // ファイルからペルソナを読み込む。空行で区切られた段落をそれぞれ一つの Part にする
+    pub fn from_file(path: &str) -> Result<SystemInstruction, GeminiError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| GeminiError::FileError(format!("Failed to read file: {}", e)))?;
+
+        let mut parts = Vec::new();
+        let mut paragraph: Vec<&str> = Vec::new();
+        for line in content.lines().chain(std::iter::once("")) {
+            if line.trim().is_empty() {
+                if !paragraph.is_empty() {
+                    parts.push(Part::Text {
+                        text: paragraph.join("\n"),
+                    });
+                    paragraph.clear();
+                }
+            } else {
+                paragraph.push(line);
+            }
+        }
+
+        Ok(SystemInstruction { role: None, parts })
+    }
+
+    // 参考資料などのファイルをシステム指示に含め、毎ターンの前提として渡す
+    pub fn with_inline_data(mut self, mime_type: &str, bytes: &[u8]) -> Self {
+        self.parts.push(Part::InlineData {
+            inline_data: InlineData::from_bytes(mime_type, bytes),
+        });
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Part {
     Text { text: String },
     FunctionCall {
         #[serde(rename = "functionCall")]
         function_call: FunctionCall,
+        // Gemini 2.x の関数呼び出しに付く署名。次のターンでそのまま送り返す必要がある
+        #[serde(rename = "thoughtSignature", skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<String>,
     },
     FunctionResponse {
         #[serde(rename = "functionResponse")]
         function_response: FunctionResponse,
     },
+    InlineData {
+        #[serde(rename = "inlineData")]
+        inline_data: InlineData,
+    },
+    ExecutableCode {
+        #[serde(rename = "executableCode")]
+        executable_code: ExecutableCode,
+    },
+    CodeExecutionResult {
+        #[serde(rename = "codeExecutionResult")]
+        code_execution_result: CodeExecutionResult,
+    },
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct GenerateContentRequest {
     pub system_instruction: SystemInstruction,
     pub contents: Vec<Content>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_config: Option<ToolConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation_config: Option<GenerationConfig>,
+    // 課金の集計用ラベル
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
 }
 
-// レスポンス用の構造体
-#[derive(Debug, Deserialize)]
-pub struct GenerateContentResponse {
-    pub candidates: Vec<Candidate>,
-}
+// ラベルは最大 64 個、キーと値はそれぞれ 63 文字までの小文字・数字・"_"・"-"
+// キーは空にできず、小文字で始まる必要がある
+const MAX_LABELS: usize = 64;
+const MAX_LABEL_LENGTH: usize = 63;
 
-#[derive(Debug, Deserialize)]
+fn validate_labels(labels: &HashMap<String, String>) -> Result<(), GeminiError> {
+    if labels.len() > MAX_LABELS {
+        return Err(GeminiError::ApiError(format!(
+            "Too many labels: {} (max {})", labels.len(), MAX_LABELS
+        )));
+    }
+    let is_label_char = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-';
+    for (key, value) in labels {
+        if !key.starts_with(|c: char| c.is_ascii_lowercase())
+            || key.chars().count() > MAX_LABEL_LENGTH
+            || !key.chars().all(is_label_char)
+        {
+            return Err(GeminiError::ApiError(format!("Invalid label key '{}'", key)));
+        }
+        if value.chars().count() > MAX_LABEL_LENGTH || !value.chars().all(is_label_char) {
+            return Err(GeminiError::ApiError(format!(
+                "Invalid value '{}' for label '{}'", value, key
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidate_count: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    // true にすると各候補に avgLogprobs と logprobsResult が付く
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_logprobs: Option<bool>,
+    // 各トークンについて返す上位候補の数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u32>,
+    // ["TEXT", "IMAGE"] のように指定すると画像も出力できるモデルがある
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_modalities: Option<Vec<String>>,
+    // 繰り返しへのペナルティ。gemini-1.0-pro など古いモデルに送ると 400 になる
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    // temperature: 0 と合わせると、対応しているモデルでは出力が毎回同じになる
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i32>,
+    // "application/json" にすると JSON で答える。responseSchema で形を指定できる
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<FunctionParameters>,
+}
+
+impl GenerationConfig {
+    // 一度出たトークンに一律でかかるペナルティ
+    pub fn with_presence_penalty(mut self, penalty: f32) -> Self {
+        self.presence_penalty = Some(penalty);
+        self
+    }
+
+    // 出た回数に応じてかかるペナルティ
+    pub fn with_frequency_penalty(mut self, penalty: f32) -> Self {
+        self.frequency_penalty = Some(penalty);
+        self
+    }
+
+    // responseLogprobs を有効にし、各トークンについて上位 top_candidates 個の候補も返させる
+    // 結果は Candidate の avg_logprobs / logprobs_result や GenerateContentResponse::avg_logprobs で見る
+    pub fn with_logprobs(mut self, top_candidates: u32) -> Self {
+        self.response_logprobs = Some(true);
+        self.logprobs = Some(top_candidates);
+        self
+    }
+
+    // 乱数のシード。with_dry_run(true) の出力で送られる値を確認できる
+    pub fn with_seed(mut self, seed: i32) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolConfig {
+    pub function_calling_config: FunctionCallingConfig,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionCallingConfig {
+    // AUTO, ANY, NONE のいずれか
+    pub mode: String,
+}
+
+impl ToolConfig {
+    pub fn mode(mode: &str) -> Self {
+        ToolConfig {
+            function_calling_config: FunctionCallingConfig {
+                mode: mode.to_string(),
+            },
+        }
+    }
+}
+
+// レスポンス用の構造体
+#[derive(Debug, Clone, Deserialize)]
+pub struct GenerateContentResponse {
+    // ストリーミングでは usageMetadata だけのイベントもある
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    // 実際に応答したモデルのバージョン (gemini-1.5-flash の中身が変わった時の確認用)
+    #[serde(rename = "modelVersion", default)]
+    pub model_version: Option<String>,
+    // Google への問い合わせ時に使うID
+    #[serde(rename = "responseId", default)]
+    pub response_id: Option<String>,
+    #[serde(rename = "usageMetadata", default)]
+    pub usage_metadata: Option<UsageMetadata>,
+}
+
+// モデルの一回の応答。テキストが無い時は text が None
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub text: Option<String>,
+    pub calls: Vec<FunctionCall>,
+    pub finish_reason: Option<FinishReason>,
+    pub usage: Option<UsageMetadata>,
+    // 会話履歴にそのまま追加できるモデルのターン。関数呼び出しの thoughtSignature も持つ
+    pub content: Content,
+}
+
+// トークンの使用量
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u32,
+    #[serde(default)]
+    pub candidates_token_count: u32,
+    #[serde(default)]
+    pub total_token_count: u32,
+}
+
+impl UsageMetadata {
+    // トークン数から計算した料金の見積もり (ドル)
+    pub fn estimated_cost(&self, model: &CostModel) -> f64 {
+        self.prompt_token_count as f64 / 1000.0 * model.input_per_1k
+            + self.candidates_token_count as f64 / 1000.0 * model.output_per_1k
+    }
+}
+
+// 1000 トークンあたりの料金 (ドル)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostModel {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+// 有料枠の料金 (128K トークン以下のプロンプト)。価格改定があるので請求額の確認には使わないこと
+impl CostModel {
+    pub const GEMINI_1_5_FLASH: CostModel = CostModel { input_per_1k: 0.000075, output_per_1k: 0.0003 };
+    pub const GEMINI_1_5_PRO: CostModel = CostModel { input_per_1k: 0.00125, output_per_1k: 0.005 };
+    pub const GEMINI_2_0_FLASH: CostModel = CostModel { input_per_1k: 0.0001, output_per_1k: 0.0004 };
+}
+
+impl GenerateContentResponse {
+    pub fn model_version(&self) -> Option<&str> {
+        self.model_version.as_deref()
+    }
+
+    pub fn response_id(&self) -> Option<&str> {
+        self.response_id.as_deref()
+    }
+
+    // 最初の候補に含まれる画像などのバイナリ出力を (MIMEタイプ, デコード済みのバイト列) で返す
+    pub fn images(&self) -> Vec<(String, Vec<u8>)> {
+        let Some(candidate) = self.candidates.first() else {
+            return vec![];
+        };
+        candidate.parts().iter()
+            .filter_map(|part| match part {
+                ResponsePart::InlineData { inline_data } => base64::engine::general_purpose::STANDARD
+                    .decode(&inline_data.data)
+                    .ok()
+                    .map(|bytes| (inline_data.mime_type.clone(), bytes)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // 最初の候補のテキストと関数呼び出し、終了理由、使用量をまとめて取り出す
+    pub fn into_turn(self) -> Turn {
+        let mut text: Option<String> = None;
+        let mut calls = Vec::new();
+        let mut finish_reason = None;
+        let mut content = Content {
+            role: "model".to_string(),
+            parts: vec![],
+        };
+        if let Some(candidate) = self.candidates.into_iter().next() {
+            finish_reason = candidate.finish_reason;
+            for part in candidate.content.map(|content| content.parts).unwrap_or_default() {
+                content.parts.push(part.to_request_part());
+                match part {
+                    ResponsePart::Text { text: delta } => text.get_or_insert_with(String::new).push_str(&delta),
+                    ResponsePart::FunctionCall { function_call, .. } => calls.push(function_call),
+                    _ => {}
+                }
+            }
+        }
+        Turn {
+            text,
+            calls,
+            finish_reason,
+            usage: self.usage_metadata,
+            content,
+        }
+    }
+
+    // ストリーミングの次のイベントを合わせる
+    // テキストは候補ごとにつなげ、終了理由や使用量などは後から届いたもので上書きする
+    fn merge_chunk(&mut self, chunk: GenerateContentResponse) {
+        for (index, candidate) in chunk.candidates.into_iter().enumerate() {
+            let Some(merged) = self.candidates.get_mut(index) else {
+                self.candidates.push(candidate);
+                continue;
+            };
+            if let Some(content) = candidate.content {
+                let parts = &mut merged.content.get_or_insert_with(ResponseContent::default).parts;
+                for part in content.parts {
+                    match (parts.last_mut(), part) {
+                        (Some(ResponsePart::Text { text }), ResponsePart::Text { text: delta }) => text.push_str(&delta),
+                        (_, part) => parts.push(part),
+                    }
+                }
+            }
+            merged.finish_reason = candidate.finish_reason.or(merged.finish_reason.take());
+            merged.citation_metadata = candidate.citation_metadata.or(merged.citation_metadata.take());
+            merged.avg_logprobs = candidate.avg_logprobs.or(merged.avg_logprobs);
+            merged.logprobs_result = candidate.logprobs_result.or(merged.logprobs_result.take());
+        }
+        self.model_version = chunk.model_version.or(self.model_version.take());
+        self.response_id = chunk.response_id.or(self.response_id.take());
+        self.usage_metadata = chunk.usage_metadata.or(self.usage_metadata.take());
+    }
+
+    // 最初の候補のトークン対数確率の平均。低い時は確信度が低い
+    pub fn avg_logprobs(&self) -> Option<f64> {
+        self.candidates.first().and_then(|candidate| candidate.avg_logprobs)
+    }
+
+    // dry run 用のダミーレスポンス。送信されるはずだったリクエストボディをテキストとして返す
+    fn dry_run_placeholder(request_body: String) -> Self {
+        GenerateContentResponse {
+            candidates: vec![Candidate {
+                content: Some(ResponseContent {
+                    parts: vec![ResponsePart::Text { text: request_body }],
+                }),
+                finish_reason: Some(FinishReason::Other("DRY_RUN".to_string())),
+                citation_metadata: None,
+                avg_logprobs: None,
+                logprobs_result: None,
+            }],
+            model_version: None,
+            response_id: None,
+            usage_metadata: None,
+        }
+    }
+}
+
+type CandidateSelectorFn = dyn Fn(&[Candidate]) -> usize;
+
+// generate_best で複数の候補から一つを選ぶ方法
+pub enum CandidateSelector {
+    First,
+    // テキストが一番長いもの
+    Longest,
+    // 選んだ候補のインデックスを返す
+    Custom(Box<CandidateSelectorFn>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Candidate {
-    pub content: ResponseContent,
+    // SAFETY や RECITATION、MALFORMED_FUNCTION_CALL などでは content が返ってこないことがある
+    #[serde(default)]
+    pub content: Option<ResponseContent>,
     #[serde(rename = "finishReason")]
-    pub finish_reason: Option<String>,
+    pub finish_reason: Option<FinishReason>,
+    #[serde(rename = "citationMetadata", default)]
+    pub citation_metadata: Option<CitationMetadata>,
+    // responseLogprobs を指定した時だけ返ってくる
+    #[serde(rename = "avgLogprobs", default)]
+    pub avg_logprobs: Option<f64>,
+    #[serde(rename = "logprobsResult", default)]
+    pub logprobs_result: Option<LogprobsResult>,
 }
 
-#[derive(Debug, Deserialize)]
+impl Candidate {
+    // テキストの Part をつなげたもの
+    pub fn text(&self) -> String {
+        self.parts().iter()
+            .filter_map(|part| match part {
+                ResponsePart::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // content の parts。content が無い場合は空
+    pub fn parts(&self) -> &[ResponsePart] {
+        self.content
+            .as_ref()
+            .map(|content| content.parts.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // モデルが実行したコードとその結果。コード実行のツールを使った時だけ含まれる
+    pub fn executable_code(&self) -> Vec<&ExecutableCode> {
+        self.parts().iter()
+            .filter_map(|part| match part {
+                ResponsePart::ExecutableCode { executable_code } => Some(executable_code),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn code_execution_results(&self) -> Vec<&CodeExecutionResult> {
+        self.parts().iter()
+            .filter_map(|part| match part {
+                ResponsePart::CodeExecutionResult { code_execution_result } => Some(code_execution_result),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // 引用元の一覧。引用がない場合は空
+    pub fn citations(&self) -> &[CitationSource] {
+        self.citation_metadata
+            .as_ref()
+            .map(|metadata| metadata.citation_sources.as_slice())
+            .unwrap_or(&[])
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CitationMetadata {
+    #[serde(rename = "citationSources", default)]
+    pub citation_sources: Vec<CitationSource>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CitationSource {
+    #[serde(rename = "startIndex")]
+    pub start_index: Option<usize>,
+    #[serde(rename = "endIndex")]
+    pub end_index: Option<usize>,
+    pub uri: Option<String>,
+    pub license: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogprobsResult {
+    // 各ステップでの上位候補
+    #[serde(rename = "topCandidates", default)]
+    pub top_candidates: Vec<TopCandidates>,
+    // 実際に選ばれたトークン
+    #[serde(rename = "chosenCandidates", default)]
+    pub chosen_candidates: Vec<LogprobsCandidate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TopCandidates {
+    #[serde(default)]
+    pub candidates: Vec<LogprobsCandidate>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogprobsCandidate {
+    pub token: Option<String>,
+    #[serde(rename = "tokenId")]
+    pub token_id: Option<i32>,
+    #[serde(rename = "logProbability")]
+    pub log_probability: Option<f64>,
+}
+
+impl CitationSource {
+    // 応答テキストのうち、この引用元に対応する部分 (インデックスはバイト単位)
+    pub fn cited_text<'a>(&self, text: &'a str) -> Option<&'a str> {
+        let start = self.start_index.unwrap_or(0);
+        let end = self.end_index?;
+        text.get(start..end)
+    }
+}
+
+// 生成が終了した理由
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    Stop,
+    MaxTokens,
+    Safety,
+    Recitation,
+    // モデルが不正な形式の関数呼び出しを出力した
+    MalformedFunctionCall,
+    Other(String),
+}
+
+impl From<&str> for FinishReason {
+    fn from(reason: &str) -> Self {
+        match reason {
+            "STOP" => FinishReason::Stop,
+            "MAX_TOKENS" => FinishReason::MaxTokens,
+            "SAFETY" => FinishReason::Safety,
+            "RECITATION" => FinishReason::Recitation,
+            "MALFORMED_FUNCTION_CALL" => FinishReason::MalformedFunctionCall,
+            other => FinishReason::Other(other.to_string()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FinishReason {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let reason = String::deserialize(deserializer)?;
+        Ok(FinishReason::from(reason.as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct ResponseContent {
     pub parts: Vec<ResponsePart>,
 }
@@ -131,88 +1311,811 @@ pub enum ResponsePart {
     Text { text: String },
     FunctionCall {
         #[serde(rename = "functionCall")]
-        function_call: FunctionCall
+        function_call: FunctionCall,
+        #[serde(rename = "thoughtSignature", default)]
+        thought_signature: Option<String>,
+    },
+    InlineData {
+        #[serde(rename = "inlineData")]
+        inline_data: InlineData,
     },
+    ExecutableCode {
+        #[serde(rename = "executableCode")]
+        executable_code: ExecutableCode,
+    },
+    CodeExecutionResult {
+        #[serde(rename = "codeExecutionResult")]
+        code_execution_result: CodeExecutionResult,
+    },
+}
+
+impl ResponsePart {
+    // モデルの応答を会話履歴に戻すためにリクエスト用の Part に変換する
+    pub fn to_request_part(&self) -> Part {
+        match self {
+            ResponsePart::Text { text } => Part::Text { text: text.clone() },
+            ResponsePart::FunctionCall { function_call, thought_signature } => Part::FunctionCall {
+                function_call: function_call.clone(),
+                thought_signature: thought_signature.clone(),
+            },
+            ResponsePart::InlineData { inline_data } => Part::InlineData {
+                inline_data: inline_data.clone(),
+            },
+            ResponsePart::ExecutableCode { executable_code } => Part::ExecutableCode {
+                executable_code: executable_code.clone(),
+            },
+            ResponsePart::CodeExecutionResult { code_execution_result } => Part::CodeExecutionResult {
+                code_execution_result: code_execution_result.clone(),
+            },
+        }
+    }
+}
+
+// {{var}} 形式のプレースホルダを持つプロンプトのテンプレート
+#[derive(Debug, Clone)]
+pub struct PromptTemplate {
+    template: String,
+}
+
+impl PromptTemplate {
+    pub fn new(template: &str) -> Self {
+        PromptTemplate {
+            template: template.to_string(),
+        }
+    }
+
+    // 値が与えられていないプレースホルダがあればエラーにする
+    pub fn render(&self, vars: &HashMap<&str, &str>) -> Result<String, GeminiError> {
+        let mut rendered = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(start) = rest.find("{{") {
+            rendered.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let end = after.find("}}")
+                .ok_or_else(|| GeminiError::ParseError("Unclosed placeholder in template".to_string()))?;
+            let name = after[..end].trim();
+            let value = vars.get(name)
+                .ok_or_else(|| GeminiError::ParseError(format!("Unresolved placeholder {{{{{}}}}}", name)))?;
+            rendered.push_str(value);
+            rest = &after[end + 2..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
+
+const DEFAULT_MODEL: &str = "gemini-1.5-flash";
+
+// continue_generation で続きを頼む最大回数
+const MAX_CONTINUATIONS: usize = 5;
+
+// ask() で関数呼び出しを繰り返す最大回数
+const DEFAULT_ASK_MAX_TURNS: usize = 10;
+
+// with_region で指定できるリージョン
+const KNOWN_REGIONS: [&str; 10] = [
+    "us-central1",
+    "us-east1",
+    "us-east4",
+    "us-west1",
+    "europe-west1",
+    "europe-west4",
+    "asia-northeast1",
+    "asia-northeast3",
+    "asia-southeast1",
+    "australia-southeast1",
+];
+
+// 音声入力として送れるMIMEタイプ
+const SUPPORTED_AUDIO_MIME_TYPES: [&str; 6] = [
+    "audio/wav",
+    "audio/mp3",
+    "audio/aiff",
+    "audio/aac",
+    "audio/ogg",
+    "audio/flac",
+];
+// リクエスト全体が 20MB を超えるとインラインでは送れない
+// base64 にすると 4/3 倍になるので、エンコード後の長さと比べる
+const MAX_INLINE_AUDIO_BYTES: usize = 20 * 1024 * 1024;
+
+// models.list で返ってくるモデルの情報
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    // "models/gemini-1.5-flash" の形
+    pub name: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub input_token_limit: Option<u32>,
+    #[serde(default)]
+    pub output_token_limit: Option<u32>,
+    #[serde(default)]
+    pub supported_generation_methods: Vec<String>,
+}
+
+// models.list のレスポンス。続きがある時は nextPageToken が返ってくる
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListModelsResponse {
+    #[serde(default)]
+    models: Vec<ModelInfo>,
+    #[serde(default)]
+    next_page_token: Option<String>,
+}
+
+// list_models で一度に取得する件数
+const LIST_MODELS_PAGE_SIZE: u32 = 50;
+
+// countTokens のレスポンス
+#[derive(Debug, Deserialize)]
+struct CountTokensResponse {
+    #[serde(rename = "totalTokens")]
+    total_tokens: usize,
+}
+
+// トークン数のおおよその見積もり。通信せずに明らかに長すぎるプロンプトを弾くためのもの
+// 「4文字で1トークン」と「1単語で4/3トークン」の平均で、正確な数が必要な時は count_tokens を使う
+pub fn estimate_tokens(text: &str) -> usize {
+    let by_chars = text.chars().count().div_ceil(4);
+    let by_words = (text.split_whitespace().count() * 4).div_ceil(3);
+    (by_chars + by_words).div_ceil(2)
+}
+
+// エラー時のレスポンスボディ
+#[derive(Debug, Deserialize)]
+struct ApiErrorBody {
+    error: ApiErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiErrorDetail {
+    message: String,
+    #[serde(default)]
+    status: Option<String>,
+    // "@type" を持つオブジェクトの配列 (google.rpc.QuotaFailure など)
+    #[serde(default)]
+    details: Vec<Value>,
 }
 
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 4 * 1024 * 1024;
+const DEFAULT_CONTENT_TYPE: &str = "application/json";
+// API のリクエストサイズの上限は 20MB
+const DEFAULT_MAX_REQUEST_BYTES: usize = 20 * 1024 * 1024;
+// with_cache(true) でキャッシュするレスポンスの件数
+const DEFAULT_CACHE_CAPACITY: usize = 128;
+// ストリーミングでデータを待つ時間の既定値。待つには読み込み用のスレッドが要るので、wasm では待ち続ける
+#[cfg(not(target_family = "wasm"))]
+const DEFAULT_STREAM_IDLE_TIMEOUT: Option<Duration> = Some(Duration::from_secs(60));
+#[cfg(target_family = "wasm")]
+const DEFAULT_STREAM_IDLE_TIMEOUT: Option<Duration> = None;
+
 // シンプルなHTTPクライアント
-pub struct SimpleHttpClient;
+// ureq::Agent を使い回すことで、接続プールとkeep-aliveが効くようにする
+#[derive(Debug, Clone)]
+pub struct SimpleHttpClient {
+    agent: ureq::Agent,
+    // Hostヘッダに使うホスト名。IPに直接接続する場合も本来のホスト名を送る
+    host: String,
+    // カスタムヘッダを付けられない環境向けに、APIキーをクエリパラメータで送る
+    use_query_key: bool,
+    // レスポンスボディの上限。WASMなどメモリの限られた環境でOOMにならないように
+    max_response_bytes: u64,
+    // POST のボディの Content-Type。charset を必須にするゲートウェイ向けに変えられる
+    content_type: String,
+}
+
+impl Default for SimpleHttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl SimpleHttpClient {
-    pub fn post(url: &str, api_key: String, body: &str) -> Result<String, GeminiError> {
+    pub fn new() -> Self {
+        Self::with_agent(Self::default_agent())
+    }
+
+    pub fn with_agent(agent: ureq::Agent) -> Self {
+        SimpleHttpClient {
+            agent,
+            host: REAL_HOST.to_string(),
+            use_query_key: false,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+        }
+    }
+
+    // TLS設定はエージェント構築時に一度だけ行う
+    fn default_agent() -> ureq::Agent {
+        ureq::Agent::new_with_config(Self::default_config())
+    }
+
+    fn default_config() -> ureq::config::Config {
         let skip_verify = ureq::tls::TlsConfig::builder()
             .disable_verification(true)
             .build();
+        // エラー時もボディを読んでメッセージを取り出すため、ステータスは自前で確認する
+        ureq::Agent::config_builder()
+            .tls_config(skip_verify)
+            .http_status_as_error(false)
+            .build()
+    }
+
+    pub fn post(&self, url: &str, api_key: &str, body: &str) -> Result<String, GeminiError> {
+        self.post_with_headers(url, api_key, body)
+            .map(|(body, _)| body)
+    }
+
+    // レスポンスヘッダ (X-RateLimit-* や Retry-After など) も一緒に返す
+    // ヘッダ名は小文字で、同じ名前が複数あれば ", " で連結する
+    // 2xx 以外の時は GeminiError::HttpStatus の headers に入る
+    pub fn post_with_headers(
+        &self,
+        url: &str,
+        api_key: &str,
+        body: &str,
+    ) -> Result<(String, HashMap<String, String>), GeminiError> {
+        let (url, mut response) = self.send_post(url, api_key, body)?;
+        let headers = Self::response_headers(&response);
+        let body = self.read_checked(&url, &mut response)?;
+        Ok((body, headers))
+    }
+
+    // SSE (text/event-stream) のボディを返す
+    // エラーのステータスやSSEでないレスポンスの時は、JSONのエラーボディを読んでエラーにする
+    pub fn post_stream(&self, url: &str, api_key: &str, body: &str) -> Result<Box<dyn Read + Send>, GeminiError> {
+        let (url, response) = self.send_post(url, api_key, body)?;
+        let status = response.status().as_u16();
+        let headers = Self::response_headers(&response);
+        self.stream_body(&url, status, headers, Box::new(response.into_body().into_reader()))
+    }
+
+    // ステータスと content-type を確かめ、SSE ならそのまま読めるように返す
+    // そうでなければ max_response_bytes までボディを読んでエラーにする
+    fn stream_body(
+        &self,
+        url: &str,
+        status: u16,
+        headers: HashMap<String, String>,
+        reader: Box<dyn Read + Send>,
+    ) -> Result<Box<dyn Read + Send>, GeminiError> {
+        let is_event_stream = headers.get("content-type")
+            .is_some_and(|value| value.starts_with("text/event-stream"));
+        if is_event_stream && (200..300).contains(&status) {
+            return Ok(reader);
+        }
+        let mut body = String::new();
+        reader.take(self.max_response_bytes)
+            .read_to_string(&mut body)
+            .map_err(|e| GeminiError::NetworkError(format!("Response read failed: {}", e)))?;
+        Err(Self::stream_error(url, status, &body, headers))
+    }
+
+    // ストリーミングで SSE 以外が返ってきた時のエラー
+    // 2xx 以外は status_error と同じ。2xx でもエラーのJSONが返ってきた時はそのメッセージを使う
+    fn stream_error(url: &str, code: u16, body: &str, headers: HashMap<String, String>) -> GeminiError {
+        if !(200..300).contains(&code) {
+            return Self::status_error(url, code, body, headers);
+        }
+        match serde_json::from_str::<ApiErrorBody>(body) {
+            Ok(ApiErrorBody { error }) => GeminiError::ApiError(error.message),
+            Err(_) => GeminiError::ApiError(format!("Expected an event stream but got: {}", body)),
+        }
+    }
+
+    // 送信先のURL (APIキーを含む場合がある) とレスポンスを返す
+    fn send_post(
+        &self,
+        url: &str,
+        api_key: &str,
+        body: &str,
+    ) -> Result<(String, ureq::http::Response<ureq::Body>), GeminiError> {
         // HTTPリクエスト作成
         // let mut request = format!("POST {} HTTP/1.1\r\n", path);
         // request.push_str(&format!("Host: {}\r\n", host));
         // request.push_str("Content-Type: application/json\r\n");
         // request.push_str(&format!("Content-Length: {}\r\n", body.len()));
-        let mut response = ureq::post(url)
-            .config().tls_config(skip_verify).build()
-            .header("Host", REAL_HOST)
-            .header("x-goog-api-key", api_key)
-            .content_type("application/json")
+        let url = self.request_url(url, api_key);
+        let mut request = self.agent.post(&url)
+            .header("Host", &self.host);
+        if !self.use_query_key {
+            request = request.header("x-goog-api-key", api_key);
+        }
+        let response = request
+            .content_type(&self.content_type)
             .send(body)
             .map_err(|e| {
                 dbg!(&e);
-                GeminiError::NetworkError(format!("Request failed: {}", e))
+                Self::request_error(&url, e)
             })?;
+        Ok((url, response))
+    }
 
-        let body = response.body_mut();
-        let response = body.read_to_string()
-            .map_err(|e| GeminiError::NetworkError(format!("Response read failed: {}", e)))?;
-        
-        Ok(response)
+    fn response_headers(response: &ureq::http::Response<ureq::Body>) -> HashMap<String, String> {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        for (name, value) in response.headers() {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            headers.entry(name.as_str().to_string())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+        headers
     }
-}
 
-// メインのクライアント
-pub struct GeminiClient {
-    api_key: String,
-    base_url: String,
+    // max_response_bytes を超えたら読むのをやめてエラーにする
+    fn read_body(&self, response: &mut ureq::http::Response<ureq::Body>) -> Result<String, GeminiError> {
+        response.body_mut()
+            .with_config()
+            .limit(self.max_response_bytes)
+            .read_to_string()
+            .map_err(|e| match e {
+                ureq::Error::BodyExceedsLimit(_) => {
+                    GeminiError::NetworkError("response exceeded max size".to_string())
+                }
+                e => GeminiError::NetworkError(format!("Response read failed: {}", e)),
+            })
+    }
 
-    system_instruction: SystemInstruction,
-    functions: Vec<FunctionDeclaration>,
-}
+    // ボディを読み、ステータスが 2xx でなければエラーにする
+    fn read_checked(&self, url: &str, response: &mut ureq::http::Response<ureq::Body>) -> Result<String, GeminiError> {
+        let status = response.status();
+        let headers = Self::response_headers(response);
+        let body = self.read_body(response)?;
+        if !status.is_success() {
+            return Err(Self::status_error(url, status.as_u16(), &body, headers));
+        }
+        Ok(body)
+    }
 
-/* curl example:
-  curl "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash:generateContent" \
-  -H "x-goog-api-key: $GEMINI_API_KEY" \
-  -H 'Content-Type: application/json' \
-  -X POST \
-  -d '{
-    "contents": [
-      {
-        "parts": [
-          {
-            "text": "How does AI work?"
-          }
-        ]
-      }
-    ]
-  }'
-*/
+    // 404 はモデル名の間違いであることが多いので、URLからモデル名を取り出して伝える
+    // 429 や 503 の Retry-After などを見られるよう、レスポンスヘッダもエラーに含める
+    fn status_error(url: &str, code: u16, body: &str, headers: HashMap<String, String>) -> GeminiError {
+        if code == 404 {
+            if let Some(model) = Self::model_from_url(url) {
+                return GeminiError::ModelNotFound(model.to_string());
+            }
+        }
+        match serde_json::from_str::<ApiErrorBody>(body) {
+            Ok(ApiErrorBody { error }) => GeminiError::HttpStatus {
+                code,
+                message: error.message,
+                status: error.status,
+                details: error.details,
+                headers,
+            },
+            Err(_) => GeminiError::HttpStatus {
+                code,
+                message: body.to_string(),
+                status: None,
+                details: vec![],
+                headers,
+            },
+        }
+    }
 
-// allow-ip-name-lookup=y にしない時はIPを直接指定する必要あり
-const BASE_IP: &str = "172.217.25.170";
-const REAL_HOST: &str = "generativelanguage.googleapis.com";
+    // ".../models/gemini-1.5-flash:generateContent" から "gemini-1.5-flash" を取り出す
+    fn model_from_url(url: &str) -> Option<&str> {
+        let start = url.find("/models/")? + "/models/".len();
+        let rest = &url[start..];
+        let end = rest.find([':', '?', '/']).unwrap_or(rest.len());
+        Some(&rest[..end])
+    }
 
-impl GeminiClient {
-    pub fn new() -> Result<Self, GeminiError> {
-        let api_key = env::var("GEMINI_API_KEY")
-            .map_err(|_| GeminiError::ApiKeyNotFound)?;
-        
-        Ok(GeminiClient {
-            api_key,
-            base_url: format!("https://{}/v1beta", REAL_HOST),
-            system_instruction: SystemInstruction {
-                parts: vec![Part::Text {
-                    text: "あなたは親切なアシスタントです。".to_string(),
-                }],
-            },
-            functions: vec![],
-        })
+    pub fn delete(&self, url: &str, api_key: &str) -> Result<String, GeminiError> {
+        let url = self.request_url(url, api_key);
+        let mut request = self.agent.delete(&url)
+            .header("Host", &self.host);
+        if !self.use_query_key {
+            request = request.header("x-goog-api-key", api_key);
+        }
+        let mut response = request
+            .call()
+            .map_err(|e| Self::request_error(&url, e))?;
+
+        self.read_checked(&url, &mut response)
+    }
+
+    // use_query_key の時は URL エンコードしたAPIキーを ?key= で付ける
+    fn request_url(&self, url: &str, api_key: &str) -> String {
+        if !self.use_query_key {
+            return url.to_string();
+        }
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}key={}", url, separator, utf8_percent_encode(api_key, NON_ALPHANUMERIC))
+    }
+
+    // 名前解決の失敗は、BASE_IP を使う回避策がわかるように区別して返す
+    fn request_error(url: &str, e: ureq::Error) -> GeminiError {
+        match e {
+            // http_status_as_error が有効なエージェントを渡された場合
+            ureq::Error::StatusCode(code) => Self::status_error(url, code, "", HashMap::new()),
+            ureq::Error::HostNotFound | ureq::Error::Timeout(ureq::Timeout::Resolve) => Self::dns_error(&e),
+            ureq::Error::Io(ref io) if Self::is_lookup_failure(io) => Self::dns_error(&e),
+            e => GeminiError::NetworkError(format!("Request failed: {}", e)),
+        }
+    }
+
+    fn dns_error(e: &ureq::Error) -> GeminiError {
+        GeminiError::NetworkError(format!(
+            "DNS resolution failed: {} (without allow-ip-name-lookup, use with_resolved_ip(BASE_IP))",
+            e
+        ))
+    }
+
+    // 標準の名前解決 (to_socket_addrs) の失敗は io::Error のまま返ってくる
+    // wasm32-wasi で名前解決が許可されていない時は Unsupported になる
+    fn is_lookup_failure(e: &std::io::Error) -> bool {
+        e.kind() == std::io::ErrorKind::Unsupported
+            || e.to_string().contains("failed to lookup address")
+    }
+
+    pub fn get(&self, url: &str, api_key: &str) -> Result<String, GeminiError> {
+        let url = self.request_url(url, api_key);
+        let mut request = self.agent.get(&url)
+            .header("Host", &self.host);
+        if !self.use_query_key {
+            request = request.header("x-goog-api-key", api_key);
+        }
+        let mut response = request
+            .call()
+            .map_err(|e| Self::request_error(&url, e))?;
+
+        self.read_checked(&url, &mut response)
+    }
+}
+
+// REAL_HOST とそのリージョンのエンドポイントを指定したIPに解決する。それ以外のホストは通常通り
+#[derive(Debug)]
+struct FixedIpResolver {
+    ip: IpAddr,
+    fallback: DefaultResolver,
+}
+
+impl FixedIpResolver {
+    fn addr_for(&self, host: Option<&str>, port: Option<u16>) -> Option<SocketAddr> {
+        host.filter(|host| host.ends_with(REAL_HOST))
+            .map(|_| SocketAddr::new(self.ip, port.unwrap_or(443)))
+    }
+}
+
+impl Resolver for FixedIpResolver {
+    fn resolve(
+        &self,
+        uri: &ureq::http::Uri,
+        config: &ureq::config::Config,
+        timeout: NextTimeout,
+    ) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        match self.addr_for(uri.host(), uri.port_u16()) {
+            Some(addr) => {
+                let mut addrs = self.empty();
+                addrs.push(addr);
+                Ok(addrs)
+            }
+            None => self.fallback.resolve(uri, config, timeout),
+        }
+    }
+}
+
+// generateContent の送信部分を差し替えるためのトレイト
+pub trait HttpTransport: Send + Sync {
+    fn post(&self, url: &str, api_key: &str, body: &str) -> Result<String, GeminiError>;
+    // モデル一覧などの GET リクエスト
+    fn get(&self, url: &str, api_key: &str) -> Result<String, GeminiError>;
+    // streamGenerateContent の SSE のボディ
+    fn post_stream(&self, url: &str, api_key: &str, body: &str) -> Result<Box<dyn Read + Send>, GeminiError>;
+}
+
+impl HttpTransport for SimpleHttpClient {
+    fn post(&self, url: &str, api_key: &str, body: &str) -> Result<String, GeminiError> {
+        SimpleHttpClient::post(self, url, api_key, body)
+    }
+
+    fn get(&self, url: &str, api_key: &str) -> Result<String, GeminiError> {
+        SimpleHttpClient::get(self, url, api_key)
+    }
+
+    fn post_stream(&self, url: &str, api_key: &str, body: &str) -> Result<Box<dyn Read + Send>, GeminiError> {
+        SimpleHttpClient::post_stream(self, url, api_key, body)
+    }
+}
+
+// カセットファイルに保存する一回分のやり取り。APIキーは保存しない
+// GET リクエストの request は空文字列になる
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CassetteEntry {
+    pub url: String,
+    pub request: String,
+    pub response: String,
+}
+
+// 実際のやり取りを JSON のカセットファイルに記録する (VCR 形式の回帰テスト用)
+pub struct RecordingTransport<T: HttpTransport> {
+    inner: T,
+    path: String,
+    entries: Mutex<Vec<CassetteEntry>>,
+}
+
+impl<T: HttpTransport> RecordingTransport<T> {
+    pub fn new(inner: T, path: &str) -> Self {
+        RecordingTransport {
+            inner,
+            path: path.to_string(),
+            entries: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl<T: HttpTransport> RecordingTransport<T> {
+    fn record(&self, url: &str, request: &str, response: &str) -> Result<(), GeminiError> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(CassetteEntry {
+            url: url.to_string(),
+            request: request.to_string(),
+            response: response.to_string(),
+        });
+        let cassette = serde_json::to_string_pretty(&*entries)
+            .map_err(|e| GeminiError::ParseError(format!("Serialization error: {}", e)))?;
+        std::fs::write(&self.path, cassette)
+            .map_err(|e| GeminiError::FileError(format!("Failed to write cassette: {}", e)))
+    }
+}
+
+impl<T: HttpTransport> HttpTransport for RecordingTransport<T> {
+    fn post(&self, url: &str, api_key: &str, body: &str) -> Result<String, GeminiError> {
+        let response = self.inner.post(url, api_key, body)?;
+        self.record(url, body, &response)?;
+        Ok(response)
+    }
+
+    fn get(&self, url: &str, api_key: &str) -> Result<String, GeminiError> {
+        let response = self.inner.get(url, api_key)?;
+        self.record(url, "", &response)?;
+        Ok(response)
+    }
+
+    // ストリーミングは記録せず、そのまま中継する
+    fn post_stream(&self, url: &str, api_key: &str, body: &str) -> Result<Box<dyn Read + Send>, GeminiError> {
+        self.inner.post_stream(url, api_key, body)
+    }
+}
+
+// 記録したカセットから、リクエストボディのハッシュが一致するレスポンスを返す
+// GET はボディが無いので URL で探す
+pub struct ReplayTransport {
+    responses: HashMap<u64, String>,
+    get_responses: HashMap<String, String>,
+}
+
+impl ReplayTransport {
+    pub fn from_file(path: &str) -> Result<Self, GeminiError> {
+        let cassette = std::fs::read_to_string(path)
+            .map_err(|e| GeminiError::FileError(format!("Failed to read cassette: {}", e)))?;
+        let entries: Vec<CassetteEntry> = serde_json::from_str(&cassette)
+            .map_err(|e| GeminiError::ParseError(format!("Invalid cassette: {}", e)))?;
+
+        let mut responses = HashMap::new();
+        let mut get_responses = HashMap::new();
+        for entry in entries {
+            if entry.request.is_empty() {
+                get_responses.insert(entry.url, entry.response);
+            } else {
+                responses.insert(Self::body_hash(&entry.request), entry.response);
+            }
+        }
+        Ok(ReplayTransport { responses, get_responses })
+    }
+
+    // ツールのパラメータやラベルは HashMap なので、キーの順番は実行するたびに変わる
+    // キーを並べ替えた JSON にしてからハッシュを取る
+    fn body_hash(body: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match serde_json::from_str::<Value>(body) {
+            Ok(value) => Self::canonical_json(&value).hash(&mut hasher),
+            Err(_) => body.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    fn canonical_json(value: &Value) -> String {
+        match value {
+            Value::Object(fields) => {
+                let mut keys: Vec<&String> = fields.keys().collect();
+                keys.sort();
+                let fields: Vec<String> = keys.into_iter()
+                    .map(|key| format!("{}:{}", Value::String(key.clone()), Self::canonical_json(&fields[key])))
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+            Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(Self::canonical_json).collect();
+                format!("[{}]", items.join(","))
+            }
+            value => value.to_string(),
+        }
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    fn post(&self, _url: &str, _api_key: &str, body: &str) -> Result<String, GeminiError> {
+        self.responses.get(&Self::body_hash(body))
+            .cloned()
+            .ok_or_else(|| GeminiError::NetworkError("No recorded response for this request".to_string()))
+    }
+
+    fn get(&self, url: &str, _api_key: &str) -> Result<String, GeminiError> {
+        self.get_responses.get(url)
+            .cloned()
+            .ok_or_else(|| GeminiError::NetworkError(format!("No recorded response for GET {}", url)))
+    }
+
+    fn post_stream(&self, _url: &str, _api_key: &str, _body: &str) -> Result<Box<dyn Read + Send>, GeminiError> {
+        Err(GeminiError::NetworkError("Streaming responses are not recorded in cassettes".to_string()))
+    }
+}
+
+// 送信直前のリクエストを書き換えるためのフック
+type InterceptorFn = dyn Fn(&mut GenerateContentRequest) + Send + Sync;
+pub type RequestInterceptor = Box<InterceptorFn>;
+
+// 登録された関数の実装。引数を受け取り、モデルに返すレスポンスを作る
+type FunctionHandlerFn = dyn Fn(&Value) -> Result<Value, GeminiError> + Send + Sync;
+// 関数呼び出しと一緒に返ってきたテキストを受け取る
+type ReasoningHandlerFn = dyn Fn(&str) + Send + Sync;
+
+// APIキーの取得元。リクエストを送るたびに呼ばれるので、キーのローテーションにも使える
+// 取得に時間がかかる場合 (シークレットマネージャーなど) は、実装側でキャッシュすること
+pub trait ApiKeySource: Send + Sync {
+    fn api_key(&self) -> Result<String, GeminiError>;
+}
+
+// 環境変数から毎回読む
+pub struct EnvApiKey {
+    var_name: String,
+}
+
+impl EnvApiKey {
+    pub fn env(var_name: &str) -> Self {
+        EnvApiKey {
+            var_name: var_name.to_string(),
+        }
+    }
+}
+
+impl ApiKeySource for EnvApiKey {
+    fn api_key(&self) -> Result<String, GeminiError> {
+        env::var(&self.var_name).map_err(|_| GeminiError::ApiKeyNotFound)
+    }
+}
+
+// 固定のキー
+pub struct StaticApiKey {
+    key: String,
+}
+
+impl StaticApiKey {
+    pub fn static_str(key: &str) -> Self {
+        StaticApiKey {
+            key: key.to_string(),
+        }
+    }
+}
+
+impl ApiKeySource for StaticApiKey {
+    fn api_key(&self) -> Result<String, GeminiError> {
+        Ok(self.key.clone())
+    }
+}
+
+// クロージャで取得する (ファイルから読む、ローテーションするなど)
+pub struct FnApiKey<F> {
+    f: F,
+}
+
+impl<F> FnApiKey<F>
+where
+    F: Fn() -> Result<String, GeminiError> + Send + Sync,
+{
+    pub fn from_fn(f: F) -> Self {
+        FnApiKey { f }
+    }
+}
+
+impl<F> ApiKeySource for FnApiKey<F>
+where
+    F: Fn() -> Result<String, GeminiError> + Send + Sync,
+{
+    fn api_key(&self) -> Result<String, GeminiError> {
+        (self.f)()
+    }
+}
+
+// メインのクライアント
+#[derive(Clone)]
+pub struct GeminiClient {
+    api_key: Arc<dyn ApiKeySource>,
+    base_url: String,
+    // "v1beta" または "v1"
+    api_version: String,
+    model: String,
+    // model が使えない時に順に試すモデル
+    model_fallbacks: Vec<String>,
+    http: SimpleHttpClient,
+    // 指定されていれば generateContent の送信に http の代わりに使う
+    transport: Option<Arc<dyn HttpTransport>>,
+
+    system_instruction: SystemInstruction,
+    functions: Vec<FunctionDeclaration>,
+    // functions とは別のツールとして送る関数群
+    tools: Vec<Tool>,
+
+    // true の時は送信せずにリクエストを表示するだけ
+    dry_run: bool,
+    request_interceptor: Option<Arc<InterceptorFn>>,
+
+    // run_agent で使う関数の実装
+    handlers: HashMap<String, Arc<FunctionHandlerFn>>,
+    reasoning_handler: Option<Arc<ReasoningHandlerFn>>,
+    // MAX_TOKENS で途切れた時に "continue" と送って続きを生成させる
+    auto_continue: bool,
+
+    generation_config: Option<GenerationConfig>,
+    labels: HashMap<String, String>,
+    // シリアライズしたリクエストボディの最大サイズ (バイト)
+    max_request_bytes: usize,
+    // true の時はコード実行のツールも送る
+    code_execution: bool,
+    // 指定されていれば、応答する言語の指示をシステム指示の先頭に付ける
+    default_language: Option<String>,
+    // ストリーミングでデータが届かない時に待つ時間。None なら待ち続ける
+    stream_idle_timeout: Option<Duration>,
+
+    // 同じリクエストの結果を使い回すためのキャッシュ。with_cache / with_response_cache の時だけ使う
+    cache: Option<Arc<Mutex<ResponseCache>>>,
+}
+
+/* curl example:
+  curl "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash:generateContent" \
+  -H "x-goog-api-key: $GEMINI_API_KEY" \
+  -H 'Content-Type: application/json' \
+  -X POST \
+  -d '{
+    "contents": [
+      {
+        "parts": [
+          {
+            "text": "How does AI work?"
+          }
+        ]
+      }
+    ]
+  }'
+*/
+
+// システム指示を指定しない時のペルソナ。generate_text では常にこれを使う
+pub const DEFAULT_SYSTEM_PROMPT: &str = "あなたは親切なアシスタントです。";
+
+const DEFAULT_API_VERSION: &str = "v1beta";
+const API_VERSIONS: [&str; 2] = ["v1beta", "v1"];
+
+// allow-ip-name-lookup=y にしない時はIPを直接指定する必要あり
+pub const BASE_IP: &str = "172.217.25.170";
+const REAL_HOST: &str = "generativelanguage.googleapis.com";
+
+impl GeminiClient {
+    pub fn new() -> Result<Self, GeminiError> {
+        let api_key = env::var("GEMINI_API_KEY")
+            .map_err(|_| GeminiError::ApiKeyNotFound)?;
+        
+        Ok(Self::with_api_key(api_key))
     }
 
     pub fn new_with_instructions(
@@ -221,127 +2124,1563 @@ impl GeminiClient {
         functions: Vec<FunctionDeclaration>,
     ) -> Self {
         GeminiClient {
-            api_key,
-            base_url: format!("https://{}/v1beta", REAL_HOST),
+            api_key: Arc::new(StaticApiKey::static_str(&api_key)),
+            base_url: format!("https://{}/{}", REAL_HOST, DEFAULT_API_VERSION),
+            api_version: DEFAULT_API_VERSION.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            model_fallbacks: vec![],
+            http: SimpleHttpClient::new(),
+            transport: None,
             system_instruction,
             functions,
+            tools: vec![],
+            dry_run: false,
+            request_interceptor: None,
+            handlers: HashMap::new(),
+            reasoning_handler: None,
+            auto_continue: false,
+            generation_config: None,
+            labels: HashMap::new(),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            code_execution: false,
+            default_language: None,
+            stream_idle_timeout: DEFAULT_STREAM_IDLE_TIMEOUT,
+            cache: None,
+        }
+    }
+
+    // APIキーを環境変数やシークレットマネージャーなどから、リクエストごとに取得する
+    pub fn with_api_key_source<S: ApiKeySource + 'static>(mut self, source: S) -> Self {
+        self.api_key = Arc::new(source);
+        self
+    }
+
+    fn api_key(&self) -> Result<String, GeminiError> {
+        self.api_key.api_key()
+    }
+
+    // 独自に設定した ureq::Agent を使う
+    pub fn with_agent(mut self, agent: ureq::Agent) -> Self {
+        self.http.agent = agent;
+        self
+    }
+
+    // 関数をグループ分けして複数のツールとして送る
+    pub fn with_tools(mut self, tools: Vec<Tool>) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    // 応答する言語 ("Japanese" や "日本語" など)。全てのリクエストのシステム指示の先頭に言語の指示を付ける
+    // generate_text_with_system のように呼び出しごとにシステム指示を変えた場合も付く
+    pub fn with_default_language(mut self, language: &str) -> Self {
+        self.default_language = Some(language.to_string());
+        self
+    }
+
+    // モデルに Python のコードを実行させる (codeExecution ツール)
+    // 実行したコードと結果は Candidate::executable_code / code_execution_results で取り出せる
+    pub fn with_code_execution(mut self, enabled: bool) -> Self {
+        self.code_execution = enabled;
+        self
+    }
+
+    // シリアライズ直前にリクエストを書き換える (generationConfig の動的な調整など)
+    pub fn with_request_interceptor(mut self, interceptor: RequestInterceptor) -> Self {
+        self.request_interceptor = Some(Arc::from(interceptor));
+        self
+    }
+
+    // 送信直前のリクエストを書き換えるフック (構造体にない実験的なフィールドの追加など)
+    // システム指示、ツール、生成設定、ラベルなど他の設定を全て反映した後に呼ばれる
+    // with_request_interceptor と同じもので、後から指定した方が使われる
+    pub fn with_request_hook(self, hook: RequestInterceptor) -> Self {
+        self.with_request_interceptor(hook)
+    }
+
+    // run_agent で関数呼び出しの前に返ってきたテキストを受け取る
+    pub fn with_reasoning_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.reasoning_handler = Some(Arc::new(handler));
+        self
+    }
+
+    // run_agent で出力が MAX_TOKENS で途切れた時に自動で続きを生成する
+    pub fn with_auto_continue(mut self, auto_continue: bool) -> Self {
+        self.auto_continue = auto_continue;
+        self
+    }
+
+    // x-goog-api-key ヘッダの代わりに ?key= でAPIキーを送る
+    pub fn with_query_key(mut self, use_query_key: bool) -> Self {
+        self.http.use_query_key = use_query_key;
+        self
+    }
+
+    // リクエストの Content-Type ("application/json; charset=utf-8" など)。省略時は "application/json"
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.http.content_type = content_type.to_string();
+        self
+    }
+
+    // レスポンスボディの最大サイズ (バイト)
+    pub fn with_max_response_bytes(mut self, max_response_bytes: u64) -> Self {
+        self.http.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    // リクエストボディの最大サイズ (バイト)。超えると送信せずに ApiError を返す
+    pub fn with_max_request_bytes(mut self, max_request_bytes: usize) -> Self {
+        self.max_request_bytes = max_request_bytes;
+        self
+    }
+
+    // ストリーミングで次のデータを待つ時間。超えるとイテレータが GeminiError::Timeout を返す
+    // None にすると接続が切れるまで待ち続ける。読み込み用のスレッドも作らないので、
+    // スレッドが使えない環境でも使える。wasm では既定で None
+    pub fn with_stream_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stream_idle_timeout = timeout;
+        self
+    }
+
+    // temperature や responseLogprobs などの生成設定
+    pub fn with_generation_config(mut self, config: GenerationConfig) -> Self {
+        self.generation_config = Some(config);
+        self
+    }
+
+    // 全てのリクエストに付ける課金集計用のラベル
+    // 使えない文字や長さのラベルは送信前に ApiError になる
+    pub fn with_labels(mut self, labels: HashMap<String, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    // 開発中に同じプロンプトでクォータを使わないよう、レスポンスをメモリにキャッシュする
+    // キャッシュはこのクライアントのインスタンスごと (clone したものとは共有) で、永続化はされない
+    // キーはモデル、システム指示、会話内容などを含むリクエスト全体のハッシュ
+    pub fn with_cache(self, enabled: bool) -> Self {
+        self.with_response_cache(if enabled { DEFAULT_CACHE_CAPACITY } else { 0 })
+    }
+
+    // 最大 capacity 件のレスポンスをキャッシュし、溢れたら最も長く使われていないものから捨てる
+    // 0 を指定するとキャッシュしない
+    pub fn with_response_cache(mut self, capacity: usize) -> Self {
+        self.cache = if capacity > 0 {
+            Some(Arc::new(Mutex::new(ResponseCache::new(capacity))))
+        } else {
+            None
+        };
+        self
+    }
+
+    // キャッシュを使わないクライアントを作る。毎回新しい応答が欲しい呼び出し向け
+    // 元のクライアントのキャッシュはそのまま残る
+    pub fn without_cache(&self) -> GeminiClient {
+        let mut client = self.clone();
+        client.cache = None;
+        client
+    }
+
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+    }
+
+    // データの所在地のため、リージョンごとのエンドポイント ({region}-generativelanguage.googleapis.com) を使う
+    // Hostヘッダも同じホスト名になる。with_resolved_ip とはどちらの順番で呼んでも併用できる
+    pub fn with_region(mut self, region: &str) -> Result<Self, GeminiError> {
+        if !KNOWN_REGIONS.contains(&region) {
+            return Err(GeminiError::ApiError(format!(
+                "Unknown region '{}' (known: {})",
+                region,
+                KNOWN_REGIONS.join(", ")
+            )));
         }
+        let host = format!("{}-{}", region, REAL_HOST);
+        self.base_url = format!("https://{}/{}", host, self.api_version);
+        self.http.host = host;
+        Ok(self)
+    }
+
+    // 名前解決せずに指定したIPへ直接接続する。URLのホストはそのままなので、SNI とHostヘッダは REAL_HOST になる
+    // REAL_HOST (リージョンのエンドポイントも含む) の名前解決だけを置き換えたエージェントを使うので、
+    // with_agent で渡したエージェントは置き換わる
+    pub fn with_resolved_ip(mut self, ip: &str) -> Result<Self, GeminiError> {
+        let ip: IpAddr = ip.parse()
+            .map_err(|e| GeminiError::ApiError(format!("Invalid IP address '{}': {}", ip, e)))?;
+        self.http.agent = ureq::Agent::with_parts(
+            SimpleHttpClient::default_config(),
+            DefaultConnector::default(),
+            FixedIpResolver { ip, fallback: DefaultResolver::default() },
+        );
+        Ok(self)
+    }
+
+    // 使うモデル ("gemini-2.0-flash" など)。省略時は gemini-1.5-flash
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    // model へのリクエストが一時的なエラーやモデルが無いことで失敗した時に、順に試すモデル
+    // 全て失敗した時は、試したモデルとそれぞれの失敗理由を含む ApiError を返す
+    // ストリーミングと countTokens では使われない
+    pub fn with_model_fallbacks(mut self, models: Vec<String>) -> Self {
+        self.model_fallbacks = models;
+        self
+    }
+
+    // 使う API のバージョン ("v1beta" または "v1")。接続先のホストはそのまま
+    pub fn with_api_version(mut self, version: &str) -> Result<Self, GeminiError> {
+        if !API_VERSIONS.contains(&version) {
+            return Err(GeminiError::ApiError(format!(
+                "Unknown API version '{}' (known: {})",
+                version,
+                API_VERSIONS.join(", ")
+            )));
+        }
+        let origin = self.base_url
+            .strip_suffix(&format!("/{}", self.api_version))
+            .unwrap_or(&self.base_url)
+            .to_string();
+        self.base_url = format!("{}/{}", origin, version);
+        self.api_version = version.to_string();
+        Ok(self)
+    }
+
+    // 送信部分を差し替える (RecordingTransport / ReplayTransport など)
+    pub fn with_transport<T: HttpTransport + 'static>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    // ネットワークに送信せず、シリアライズしたリクエストを確認する
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
     }
     
     pub fn with_api_key(api_key: String) -> Self {
-        GeminiClient {
-            api_key,
-            base_url: format!("https://{}/v1beta", REAL_HOST),
-            system_instruction: SystemInstruction {
-                parts: vec![Part::Text {
-                    text: "あなたは親切なアシスタントです。".to_string(),
-                }],
-            },
-            functions: vec![],
+        Self::with_functions(api_key, vec![])
+    }
+
+    // DEFAULT_SYSTEM_PROMPT だけのシステム指示
+    pub fn default_system_instruction() -> SystemInstruction {
+        SystemInstruction {
+            role: None,
+            parts: vec![Part::Text {
+                text: DEFAULT_SYSTEM_PROMPT.to_string(),
+            }],
         }
     }
+
+    // デフォルトのペルソナのまま関数だけを登録する
+    pub fn with_functions(api_key: String, functions: Vec<FunctionDeclaration>) -> Self {
+        Self::new_with_instructions(
+            api_key,
+            Self::default_system_instruction(),
+            functions,
+        )
+    }
     
     // テキスト生成
+    // 関数やツールを登録していても tools は送らない (text_request を参照)
     pub fn generate_text(&self, prompt: &str) -> Result<String, GeminiError> {
-        let request = GenerateContentRequest {
-            system_instruction: SystemInstruction {
-                parts: vec![Part::Text {
-                    text: "あなたは親切なアシスタントです。".to_string(),
-                }],
-            },
-            contents: vec![Content {
-                role: "user".to_string(),
-                parts: vec![Part::Text {
-                    text: prompt.to_string(),
-                }],
+        Self::check_prompt(prompt)?;
+        let request = self.text_request(prompt);
+        let response = self.generate_content(&request)?;
+        Self::first_text(&response)
+    }
+
+    // generate_text と同じだが、STOP で終わったのにパーツが空の応答が返ってきた時は一度だけ送り直す
+    // 送り直しても空なら ApiError("empty response") を返す
+    pub fn generate_text_resilient(&self, prompt: &str) -> Result<String, GeminiError> {
+        Self::check_prompt(prompt)?;
+        let request = self.text_request(prompt);
+        let response = self.generate_content(&request)?;
+        if !Self::is_empty_response(&response) {
+            return Self::first_text(&response);
+        }
+
+        // 空の応答がキャッシュされていることがあるので、キャッシュを使わずに送り直す
+        let response = self.without_cache().generate_content(&request)?;
+        if Self::is_empty_response(&response) {
+            return Err(GeminiError::ApiError("empty response".to_string()));
+        }
+        Self::first_text(&response)
+    }
+
+    // 正常に終わった (STOP) のに、どの候補にもパーツが無い
+    fn is_empty_response(response: &GenerateContentResponse) -> bool {
+        !response.candidates.is_empty()
+            && response.candidates.iter().all(|candidate| {
+                candidate.parts().is_empty() && candidate.finish_reason == Some(FinishReason::Stop)
+            })
+    }
+
+    // この呼び出しだけ system をシステム指示にして generate_text する
+    pub fn generate_text_with_system(&self, system: &str, prompt: &str) -> Result<String, GeminiError> {
+        Self::check_prompt(prompt)?;
+        let mut request = self.text_request(prompt);
+        request.system_instruction = SystemInstruction {
+            role: None,
+            parts: vec![Part::Text {
+                text: system.to_string(),
             }],
-            tools: None,
         };
-        
         let response = self.generate_content(&request)?;
+        Self::first_text(&response)
+    }
+
+    // generate_text と同じだが、MAX_TOKENS で途中で切れたかどうかも返す
+    // true の時は続きを生成させるか maxOutputTokens を増やす
+    pub fn generate_text_checked(&self, prompt: &str) -> Result<(String, bool), GeminiError> {
+        let request = self.text_request(prompt);
+        let response = self.generate_content(&request)?;
+        let text = Self::first_text(&response)?;
+        let truncated = response.candidates.first()
+            .is_some_and(|candidate| candidate.finish_reason == Some(FinishReason::MaxTokens));
+        Ok((text, truncated))
+    }
+
+    // MAX_TOKENS で途切れた出力の続きを "continue" と送って生成させ、つなげたテキストを返す
+    // 最後が途切れたモデルのターンならその続きから、ユーザーのターンならそこから生成する
+    // 生成したモデルのターンは conversation に追加される。続きを頼むのは MAX_CONTINUATIONS 回まで
+    pub fn continue_generation(&self, conversation: &mut Vec<Content>) -> Result<String, GeminiError> {
+        let mut text = String::new();
+        for continuation in 0..=MAX_CONTINUATIONS {
+            if continuation > 0 || conversation.last().is_some_and(|content| content.role == Role::Model.as_str()) {
+                conversation.push(Content::builder(Role::User).text("continue").build());
+            }
+            let request = self.build_request(conversation.clone());
+            let response = self.generate_content(&request)?;
+            let candidate = response.candidates.into_iter().next()
+                .ok_or_else(|| GeminiError::ApiError("No candidates found in response".to_string()))?;
+            // 空のモデルのターンを履歴に入れると、次のリクエストが送れなくなる
+            if candidate.parts().is_empty() {
+                return Err(Self::no_content_error(std::slice::from_ref(&candidate)));
+            }
+
+            for part in candidate.parts() {
+                if let ResponsePart::Text { text: delta } = part {
+                    text.push_str(delta);
+                }
+            }
+            conversation.push(Self::model_content(candidate.parts()));
+            if candidate.finish_reason != Some(FinishReason::MaxTokens) {
+                break;
+            }
+        }
+        Ok(text)
+    }
+
+    // JSON モードで T の形の JSON を生成させ、T にデシリアライズして返す
+    // スキーマは schema_for::<T>() で作る
+    pub fn generate_json_as<T: DeserializeOwned>(&self, prompt: &str) -> Result<T, GeminiError> {
+        Self::check_prompt(prompt)?;
+        let mut request = self.text_request(prompt);
+        let config = request.generation_config.get_or_insert_with(GenerationConfig::default);
+        config.response_mime_type = Some("application/json".to_string());
+        config.response_schema = Some(schema_for::<T>()?);
+
+        let response = self.generate_content(&request)?;
+        let text = Self::first_text(&response)?;
+        serde_json::from_str(&text).map_err(|e| GeminiError::ParseError(format!(
+            "Model response does not match the requested schema: {} (response: {})", e, text
+        )))
+    }
+
+    // n 個の候補を生成させ、selector で選んだ候補のテキストを返す
+    pub fn generate_best(&self, prompt: &str, n: i32, selector: CandidateSelector) -> Result<String, GeminiError> {
+        let mut request = self.text_request(prompt);
+        request.generation_config.get_or_insert_with(GenerationConfig::default).candidate_count = Some(n);
+        let response = self.generate_content(&request)?;
+        if response.candidates.is_empty() {
+            return Err(GeminiError::ApiError("No candidates found in response".to_string()));
+        }
+
+        let index = match &selector {
+            CandidateSelector::First => 0,
+            CandidateSelector::Longest => response.candidates.iter()
+                .enumerate()
+                .max_by_key(|(_, candidate)| candidate.text().len())
+                .map(|(index, _)| index)
+                .unwrap_or(0),
+            CandidateSelector::Custom(select) => select(&response.candidates),
+        };
+        response.candidates.get(index)
+            .map(|candidate| candidate.text())
+            .ok_or_else(|| GeminiError::ApiError(format!(
+                "Selected candidate {} is out of range ({} candidates)", index, response.candidates.len()
+            )))
+    }
+
+    // generate_text と同じリクエストを送り、最初の候補をそのまま返す
+    // finishReason や引用、logprobs などを見たい時に使う
+    pub fn generate_full(&self, prompt: &str) -> Result<Candidate, GeminiError> {
+        let request = self.text_request(prompt);
+        let response = self.generate_content(&request)?;
+        response.candidates.into_iter().next()
+            .ok_or_else(|| GeminiError::ApiError("No candidates found in response".to_string()))
+    }
+
+    // テキスト生成用のリクエスト。ツールは送らない
+    fn text_request(&self, prompt: &str) -> GenerateContentRequest {
+        GenerateContentRequest {
+            system_instruction: Self::default_system_instruction(),
+            contents: vec![Content {
+                role: "user".to_string(),
+                parts: vec![Part::Text {
+                    text: prompt.to_string(),
+                }],
+            }],
+            tools: None,
+            tool_config: None,
+            generation_config: self.generation_config.clone(),
+            labels: self.request_labels(),
+        }
+    }
+
+    // 関数が登録されていても、この呼び出しではツールを使わせない
+    pub fn generate_text_no_tools(&self, prompt: &str) -> Result<String, GeminiError> {
+        let mut request = self.build_request(vec![
+            Content::builder(Role::User).text(prompt).build(),
+        ]);
+        request.tools = None;
+        request.tool_config = Some(ToolConfig::mode("NONE"));
+
+        let response = self.generate_content(&request)?;
+        Self::first_text(&response)
+    }
+
+    // 空のプロンプトは API が 400 を返すので、送る前に弾く
+    fn check_prompt(prompt: &str) -> Result<(), GeminiError> {
+        if prompt.trim().is_empty() {
+            return Err(GeminiError::ApiError("prompt is empty".to_string()));
+        }
+        Ok(())
+    }
+
+    // テキストを持つ最初の候補のテキストを返す
+    // 最初の候補が SAFETY などで止められていても、後の候補にテキストがあればそれを使う
+    fn first_text(response: &GenerateContentResponse) -> Result<String, GeminiError> {
+        // 音声の応答などでは、テキストの前に別の Part が来ることがある
+        for candidate in &response.candidates {
+            let text = candidate.text();
+            if !text.is_empty() {
+                return Ok(text);
+            }
+        }
+        Err(Self::no_content_error(&response.candidates))
+    }
+
+    // テキストや Part を持たない候補しか無い時のエラー
+    // SAFETY や RECITATION で止められた時は Blocked、それ以外は止まった理由を含む ApiError にする
+    fn no_content_error(candidates: &[Candidate]) -> GeminiError {
+        let blocked: Vec<String> = candidates.iter()
+            .filter_map(|candidate| match &candidate.finish_reason {
+                Some(reason @ (FinishReason::Safety | FinishReason::Recitation)) => Some(format!("{:?}", reason)),
+                _ => None,
+            })
+            .collect();
+        if !blocked.is_empty() {
+            return GeminiError::Blocked(format!(
+                "All candidates were blocked (finish reasons: {})", blocked.join(", ")
+            ));
+        }
+        // content が無い候補は、止まった理由をエラーに含める
+        if let Some(candidate) = candidates.first() {
+            if candidate.content.is_none() {
+                if let Some(reason) = &candidate.finish_reason {
+                    return GeminiError::ApiError(format!(
+                        "No text response found (finish reason: {:?})", reason
+                    ));
+                }
+            }
+        }
+
+        GeminiError::ApiError("No text response found".to_string())
+    }
+    
+    // ファイルを読み込んで要約させる。UTF-8でない部分は置換文字にして送る
+    pub fn summarize_file(&self, path: &str) -> Result<String, GeminiError> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| GeminiError::FileError(format!("Failed to read file: {}", e)))?;
+        let content = String::from_utf8_lossy(&bytes);
+
+        let prompt = format!(
+            "次のファイルの内容を要約してください。\n\nファイル名: {}\n\n{}",
+            path, content
+        );
+        self.generate_text(&prompt)
+    }
+
+    // 音声とプロンプトを送ってテキストを生成する
+    // 音声入力に対応しているのは gemini-1.5-flash / gemini-1.5-pro / gemini-2.0-flash 以降のモデル
+    pub fn generate_with_audio(
+        &self,
+        prompt: &str,
+        audio_bytes: &[u8],
+        mime_type: &str,
+    ) -> Result<String, GeminiError> {
+        if !SUPPORTED_AUDIO_MIME_TYPES.contains(&mime_type) {
+            return Err(GeminiError::ApiError(format!(
+                "Unsupported audio mime type '{}' (supported: {})",
+                mime_type,
+                SUPPORTED_AUDIO_MIME_TYPES.join(", ")
+            )));
+        }
+        let encoded_len = audio_bytes.len().div_ceil(3) * 4;
+        if encoded_len > MAX_INLINE_AUDIO_BYTES {
+            return Err(GeminiError::ApiError(format!(
+                "Audio is too large to send inline ({} bytes, {} bytes as base64); upload it with the Files API instead",
+                audio_bytes.len(),
+                encoded_len
+            )));
+        }
+
+        let request = self.build_request(vec![
+            Content::builder(Role::User)
+                .text(prompt)
+                .audio(mime_type, audio_bytes)
+                .build(),
+        ]);
+        let response = self.generate_content(&request)?;
+        Self::first_text(&response)
+    }
+
+    // テンプレートに値を埋め込んでテキスト生成
+    pub fn generate_from_template(
+        &self,
+        template: &PromptTemplate,
+        vars: &HashMap<&str, &str>,
+    ) -> Result<String, GeminiError> {
+        let prompt = template.render(vars)?;
+        self.generate_text(&prompt)
+    }
+    
+    // 独立した複数のプロンプトを最大 concurrency 個のスレッドで並列に処理する
+    // 結果は prompts と同じ順番で返る。HTTPのエージェントはスレッド間で共有される
+    // APIキーが拒否されたら (401 / 403 / API_KEY_INVALID)、まだ送っていないプロンプトは送らずに AuthError にする
+    pub fn generate_batch(&self, prompts: &[&str], concurrency: usize) -> Vec<Result<String, GeminiError>> {
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<Result<String, GeminiError>>>> =
+            Mutex::new(prompts.iter().map(|_| None).collect());
+        let auth_failure: Mutex<Option<String>> = Mutex::new(None);
+
+        // 各スレッドで動かす処理。参照しか持たないのでコピーして使い回せる
+        let work = || loop {
+            let index = next.fetch_add(1, Ordering::SeqCst);
+            let Some(prompt) = prompts.get(index) else {
+                break;
+            };
+            if let Some(message) = auth_failure.lock().unwrap().clone() {
+                results.lock().unwrap()[index] = Some(Err(GeminiError::AuthError(format!(
+                    "skipped after an earlier authentication failure: {}", message
+                ))));
+                continue;
+            }
+            let result = match self.generate_text(prompt) {
+                Err(e) if e.is_invalid_api_key() => {
+                    let message = e.to_string();
+                    *auth_failure.lock().unwrap() = Some(message.clone());
+                    Err(GeminiError::AuthError(message))
+                }
+                result => result,
+            };
+            results.lock().unwrap()[index] = Some(result);
+        };
+        thread::scope(|scope| {
+            for _ in 0..concurrency.clamp(1, prompts.len().max(1)) {
+                // スレッドが作れない環境 (wasm32-wasi など) では、残りをこのスレッドで順に処理する
+                if thread::Builder::new().spawn_scoped(scope, work).is_err() {
+                    work();
+                    break;
+                }
+            }
+        });
+
+        results.into_inner().unwrap()
+            .into_iter()
+            .map(|result| result.expect("every prompt should have been processed"))
+            .collect()
+    }
+
+    // generate_batch と同じ。同時に実行するリクエスト数を max_concurrency で制限する
+    // ureq::Agent は内部で接続プールを共有しており、スレッド間で安全に使える
+    pub fn generate_texts_concurrent(
+        &self,
+        prompts: &[&str],
+        max_concurrency: usize,
+    ) -> Vec<Result<String, GeminiError>> {
+        self.generate_batch(prompts, max_concurrency)
+    }
+    
+    // Function Callingを使った生成
+    pub fn generate_with_functions(
+        &self, 
+        prompt: &str, 
+    ) -> Result<GenerateContentResponse, GeminiError> {
+        Self::check_prompt(prompt)?;
+        let request = self.build_request(vec![Content {
+            role: "user".to_string(),
+            parts: vec![Part::Text {
+                text: prompt.to_string(),
+            }],
+        }]);
+        
+        self.generate_content(&request)
+    }
+
+    // 組み立て済みの Content をそのまま送る。システム指示とツールはクライアントのものを使う
+    pub fn generate_from_contents(&self, contents: Vec<Content>) -> Result<GenerateContentResponse, GeminiError> {
+        let request = self.build_request(contents);
+        self.generate_content(&request)
+    }
+
+    // history の後にプロンプトを付けて Function Calling を行う
+    // 関数呼び出しとその結果の例を history に入れておくと、ツールを選ぶ精度が上がる
+    pub fn generate_with_functions_history(
+        &self,
+        history: &[Content],
+        prompt: &str,
+    ) -> Result<GenerateContentResponse, GeminiError> {
+        let mut contents = history.to_vec();
+        contents.push(Content::builder(Role::User).text(prompt).build());
+        let request = self.build_request(contents);
+
+        self.generate_content(&request)
+    }
+    
+    // generate_with_functions と同じだが、tools_enabled が false の時はこの呼び出しだけツールを送らない
+    pub fn generate_with_tools_enabled(
+        &self,
+        prompt: &str,
+        tools_enabled: bool,
+    ) -> Result<GenerateContentResponse, GeminiError> {
+        let mut request = self.build_request(vec![
+            Content::builder(Role::User).text(prompt).build(),
+        ]);
+        if !tools_enabled {
+            request.tools = None;
+            request.tool_config = Some(ToolConfig::mode("NONE"));
+        }
+
+        self.generate_content(&request)
+    }
+
+    // 関数が登録されていれば Function Calling のループまで行い、最終的なテキストを返す
+    // 関数が呼ばれなかった時は一回の生成で終わる。generate_text と違い、
+    // DEFAULT_SYSTEM_PROMPT ではなくクライアントのシステム指示とツールをそのまま使う
+    // SAFETY などで止められた時は generate_text と同じく GeminiError::Blocked になる
+    pub fn ask(&self, prompt: &str) -> Result<String, GeminiError> {
+        self.run_agent(prompt, DEFAULT_ASK_MAX_TURNS)
+            .map(|result| result.text)
+    }
+
+    // 内部ではストリーミングで受け取り、全てのイベントをまとめた一つのレスポンスを返す
+    // 結果は generate_content と同じ形で、使用量は最後のイベントのものになる
+    pub fn generate_streamed(&self, prompt: &str) -> Result<GenerateContentResponse, GeminiError> {
+        let request = self.build_request(vec![
+            Content::builder(Role::User).text(prompt).build(),
+        ]);
+        let mut merged: Option<GenerateContentResponse> = None;
+        for chunk in self.stream_content(&request)? {
+            let chunk = chunk?;
+            match merged.as_mut() {
+                Some(response) => response.merge_chunk(chunk),
+                None => merged = Some(chunk),
+            }
+        }
+        merged.ok_or_else(|| GeminiError::ApiError("Stream ended without any response".to_string()))
+    }
+
+    // Function Calling をストリーミングで行う
+    // テキストは届いた順に StreamEvent::Text で返し、関数呼び出しは最後にまとめて返す
+    pub fn stream_with_functions(&self, prompt: &str) -> Result<StreamEvents, GeminiError> {
+        let request = self.build_request(vec![
+            Content::builder(Role::User).text(prompt).build(),
+        ]);
+        let chunks = self.stream_content(&request)?;
+        Ok(StreamEvents::new(chunks))
+    }
+    
+    // Function Callの結果を送信
+    // conversation には to_request_part で作ったモデルのターンを入れておくこと (thoughtSignature が保持される)
+    pub fn continue_with_function_result(
+        &self,
+        conversation: &mut Vec<Content>,
+        function_name: &str,
+        result: serde_json::Value,
+    ) -> Result<GenerateContentResponse, GeminiError> {
+        conversation.push(Content::function_result(function_name, result));
+        
+        let request = self.build_request(conversation.clone());
+        
+        self.generate_content(&request)
+    }
+    
+    // 関数の失敗をエラーコード付きで返して続ける
+    // {"error": {"code": code, "message": message}} を関数の結果として送る
+    pub fn continue_with_function_error(
+        &self,
+        conversation: &mut Vec<Content>,
+        function_name: &str,
+        code: &str,
+        message: &str,
+    ) -> Result<GenerateContentResponse, GeminiError> {
+        let error = serde_json::json!({
+            "error": {
+                "code": code,
+                "message": message,
+            },
+        });
+        self.continue_with_function_result(conversation, function_name, error)
+    }
+
+    // モデルの関数呼び出しのターンと関数の結果を正しいロールで履歴に追加して送信する
+    // 応答の Part をそのまま受け取り、thoughtSignature も一緒に送り返す
+    pub fn respond_to_function_call(
+        &self,
+        history: &mut Conversation,
+        part: &ResponsePart,
+        result: Value,
+    ) -> Result<GenerateContentResponse, GeminiError> {
+        let ResponsePart::FunctionCall { function_call, .. } = part else {
+            return Err(GeminiError::ApiError("Expected a function call part".to_string()));
+        };
+        history.push(Content {
+            role: "model".to_string(),
+            parts: vec![part.to_request_part()],
+        });
+        self.continue_with_function_result(history, &function_call.name, result)
+    }
+
+    // システム指示の最後に Part を追加する。基本のペルソナに場面ごとの指示を足す時に使う
+    pub fn add_system_part(&mut self, part: Part) {
+        self.system_instruction.parts.push(part);
+    }
+
+    // 関数の宣言と実装を登録する
+    pub fn register_function<F>(&mut self, declaration: FunctionDeclaration, handler: F)
+    where
+        F: Fn(&Value) -> Result<Value, GeminiError> + Send + Sync + 'static,
+    {
+        self.handlers.insert(declaration.name.clone(), Arc::new(handler));
+        self.functions.push(declaration);
+    }
+
+    // 引数と結果を型で扱う関数を登録する
+    // 引数が T にデシリアライズできない時は、そのエラーが失敗としてモデルに返される
+    pub fn register_typed_function<T, R, F>(&mut self, declaration: FunctionDeclaration, handler: F)
+    where
+        T: DeserializeOwned,
+        R: Serialize,
+        F: Fn(T) -> Result<R, GeminiError> + Send + Sync + 'static,
+    {
+        self.register_function(declaration, move |args| {
+            let args = serde_json::from_value(args.clone())
+                .map_err(|e| GeminiError::ParseError(format!("Invalid function arguments: {}", e)))?;
+            let result = handler(args)?;
+            serde_json::to_value(result)
+                .map_err(|e| GeminiError::ParseError(format!("Serialization error: {}", e)))
+        });
+    }
+
+    // 引数の型 T からスキーマを作って関数を登録する。宣言と実装の引数がずれないようにするためのもの
+    // スキーマの作り方と制限は schema_for を参照
+    pub fn register_fn<T, R, F>(&mut self, name: &str, description: &str, handler: F) -> Result<(), GeminiError>
+    where
+        T: DeserializeOwned,
+        R: Serialize,
+        F: Fn(T) -> Result<R, GeminiError> + Send + Sync + 'static,
+    {
+        let declaration = FunctionDeclaration {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters: schema_for::<T>()?,
+        };
+        self.register_typed_function(declaration, handler);
+        Ok(())
+    }
+
+    // 関数の宣言を確認する。同じ名前の関数が複数あるとどれが呼ばれたのか区別できない
+    // 送信時にも確認されるが、設定の間違いを最初のリクエストより前に見つけたい時に使う
+    pub fn validate_functions(&self) -> Result<(), GeminiError> {
+        let declarations = || {
+            self.functions.iter().chain(self.tools.iter().flat_map(|tool| &tool.function_declarations))
+        };
+        for declaration in declarations() {
+            declaration.validate()?;
+        }
+        check_duplicate_functions(declarations())
+    }
+
+    // 登録されている関数の宣言。ヘルプやメニューの表示用
+    pub fn functions(&self) -> &[FunctionDeclaration] {
+        &self.functions
+    }
+
+    pub fn function_names(&self) -> Vec<&str> {
+        self.functions.iter().map(|function| function.name.as_str()).collect()
+    }
+
+    // 登録した関数とツールをすべて外す。コード実行 (with_code_execution) も無効にする
+    pub fn clear_functions(&mut self) {
+        self.functions.clear();
+        self.tools.clear();
+        self.handlers.clear();
+        self.code_execution = false;
+    }
+
+    // 関数を持たないクライアントを作る。最後の要約など、ツールを呼ばせたくないターン向け
+    pub fn without_functions(&self) -> GeminiClient {
+        let mut client = self.clone();
+        client.clear_functions();
+        client
+    }
+
+    // モデルが関数を呼ばなくなるまで、関数の実行と結果の送信を繰り返す
+    pub fn run_agent(&self, prompt: &str, max_turns: usize) -> Result<AgentResult, GeminiError> {
+        Self::check_prompt(prompt)?;
+        let mut conversation = vec![Content {
+            role: "user".to_string(),
+            parts: vec![Part::Text {
+                text: prompt.to_string(),
+            }],
+        }];
+        self.run_agent_continue(&mut conversation, max_turns)
+    }
+
+    // 保存しておいた会話履歴から run_agent のループを再開する
+    // conversation にはやり取りが追記されていくので、中断した後にそのまま保存し直せる
+    pub fn run_agent_continue(
+        &self,
+        conversation: &mut Conversation,
+        max_turns: usize,
+    ) -> Result<AgentResult, GeminiError> {
+        // MAX_TOKENS で続きを生成させた場合、途中までのテキストをここに貯める
+        let mut answer = String::new();
+        // 不正な関数呼び出しは一度だけやり直させる
+        let mut retried_malformed_call = false;
+        let mut function_calls_made = 0;
+
+        for turn in 1..=max_turns {
+            let request = self.build_request(conversation.clone());
+            let response = self.generate_content(&request)?;
+            let candidate = response.candidates.into_iter().next()
+                .ok_or_else(|| GeminiError::ApiError("No candidates found in response".to_string()))?;
+
+            if candidate.finish_reason == Some(FinishReason::MalformedFunctionCall) {
+                if retried_malformed_call {
+                    return Err(GeminiError::ApiError(
+                        "Model produced a malformed function call again after retry".to_string(),
+                    ));
+                }
+                retried_malformed_call = true;
+                conversation.push(Content {
+                    role: "user".to_string(),
+                    parts: vec![Part::Text {
+                        text: "The previous function call was malformed. Call the function again with valid arguments that match its declared parameters.".to_string(),
+                    }],
+                });
+                continue;
+            }
+            // SAFETY などで content が無い時は、空のモデルのターンを履歴に残さずにエラーにする
+            if candidate.parts().is_empty() {
+                return Err(Self::no_content_error(std::slice::from_ref(&candidate)));
+            }
+
+            let mut texts = Vec::new();
+            let mut calls = Vec::new();
+            for part in candidate.parts() {
+                match part {
+                    ResponsePart::Text { text } => texts.push(text.as_str()),
+                    ResponsePart::FunctionCall { function_call, .. } => calls.push(function_call),
+                    _ => {}
+                }
+            }
+
+            if calls.is_empty() {
+                answer.push_str(&texts.concat());
+                if self.auto_continue && candidate.finish_reason == Some(FinishReason::MaxTokens) {
+                    conversation.push(Self::model_content(candidate.parts()));
+                    conversation.push(Content {
+                        role: "user".to_string(),
+                        parts: vec![Part::Text {
+                            text: "continue".to_string(),
+                        }],
+                    });
+                    continue;
+                }
+                // auto_continue が無効なら、途切れていても途中までのテキストを返す
+                // 最後の応答も履歴に残し、run_agent_continue で続きから再開できるようにする
+                conversation.push(Self::model_content(candidate.parts()));
+                return Ok(AgentResult {
+                    text: answer,
+                    turns_used: turn,
+                    function_calls_made,
+                    truncated: candidate.finish_reason == Some(FinishReason::MaxTokens),
+                });
+            }
+
+            // 関数呼び出しに添えられたテキストは考えとして通知する
+            // パーツは応答の順に処理し、テキストはその後ろにある関数を実行する前に通知する
+            function_calls_made += calls.len();
+            let mut responses = Vec::new();
+            for part in candidate.parts() {
+                match part {
+                    ResponsePart::Text { text } => {
+                        if let Some(reasoning_handler) = &self.reasoning_handler {
+                            reasoning_handler(text);
+                        }
+                    }
+                    ResponsePart::FunctionCall { function_call, .. } => responses.push(Part::FunctionResponse {
+                        function_response: self.call_function(function_call),
+                    }),
+                    _ => {}
+                }
+            }
+            conversation.push(Self::model_content(candidate.parts()));
+            conversation.push(Content {
+                role: "user".to_string(),
+                parts: responses,
+            });
+        }
+
+        Err(GeminiError::MaxTurnsExceeded(conversation.clone()))
+    }
+
+    // モデルの応答を会話履歴に追加できる形にする
+    fn model_content(parts: &[ResponsePart]) -> Content {
+        Content {
+            role: "model".to_string(),
+            parts: parts.iter().map(ResponsePart::to_request_part).collect(),
+        }
+    }
+
+    // 登録された関数を実行する。失敗した場合もエラー内容をモデルに返す
+    fn call_function(&self, call: &FunctionCall) -> FunctionResponse {
+        let result = match self.handlers.get(&call.name) {
+            Some(handler) => handler(&call.args),
+            None => Err(GeminiError::ApiError(format!("Unknown function: {}", call.name))),
+        };
+
+        match result {
+            Ok(value) => FunctionResponse::new(&call.name, value),
+            Err(e) => FunctionResponse::error(&call.name, &e.to_string()),
+        }
+    }
+
+    // クライアントのシステム指示とツールを使ってリクエストを組み立てる
+    fn build_request(&self, contents: Vec<Content>) -> GenerateContentRequest {
+        GenerateContentRequest {
+            system_instruction: self.system_instruction.clone(),
+            contents,
+            tools: self.request_tools(),
+            tool_config: None,
+            generation_config: self.generation_config.clone(),
+            labels: self.request_labels(),
+        }
+    }
+
+    // ラベルが無い時は labels 自体を送らない
+    fn request_labels(&self) -> Option<HashMap<String, String>> {
+        if self.labels.is_empty() {
+            None
+        } else {
+            Some(self.labels.clone())
+        }
+    }
+
+    // functions を一つのツールにまとめ、with_tools で指定したツールと合わせる
+    fn request_tools(&self) -> Option<Vec<Tool>> {
+        let mut tools = Vec::new();
+        if !self.functions.is_empty() {
+            tools.push(Tool {
+                function_declarations: self.functions.clone(),
+                code_execution: None,
+            });
+        }
+        if self.code_execution {
+            tools.push(Tool {
+                function_declarations: vec![],
+                code_execution: Some(CodeExecution {}),
+            });
+        }
+        tools.extend(self.tools.iter().cloned());
+
+        if tools.is_empty() {
+            None
+        } else {
+            Some(tools)
+        }
+    }
+
+    // 起動時に一度呼んで、APIキーが使えるかどうかを確かめる
+    // モデル一覧を1件だけ取得する軽いリクエストを送る
+    pub fn validate_key(&self) -> Result<(), GeminiError> {
+        let url = format!("{}/models?pageSize=1", self.base_url);
+        match self.get(&url) {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_invalid_api_key() => Err(GeminiError::AuthError(
+                format!("API key was rejected ({})", e),
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    // 使えるモデルの一覧。nextPageToken をたどって全てのページを取得する
+    pub fn list_models(&self) -> Result<Vec<ModelInfo>, GeminiError> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url = format!("{}/models?pageSize={}", self.base_url, LIST_MODELS_PAGE_SIZE);
+            if let Some(token) = &page_token {
+                url.push_str("&pageToken=");
+                url.push_str(&utf8_percent_encode(token, NON_ALPHANUMERIC).to_string());
+            }
+            let body = self.get(&url)?;
+            let page: ListModelsResponse = serde_json::from_str(&body)
+                .map_err(|e| GeminiError::ParseError(format!("Deserialization error: {}", e)))?;
+            models.extend(page.models);
+
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => return Ok(models),
+            }
+        }
+    }
+
+    // GET も with_transport で差し替えた送信部分を通す
+    fn get(&self, url: &str) -> Result<String, GeminiError> {
+        match &self.transport {
+            Some(transport) => transport.get(url, &self.api_key()?),
+            None => self.http.get(url, &self.api_key()?),
+        }
+    }
+
+    // countTokens でプロンプトの正確なトークン数を数える
+    // 通信せずにおおよその数が知りたい時は estimate_tokens を使う
+    pub fn count_tokens(&self, prompt: &str) -> Result<usize, GeminiError> {
+        let url = format!("{}/models/{}:countTokens", self.base_url, self.model);
+        let body = serde_json::json!({
+            "contents": [Content::builder(Role::User).text(prompt).build()],
+        });
+        let response = self.http.post(&url, &self.api_key()?, &body.to_string())?;
+        let counted: CountTokensResponse = serde_json::from_str(&response)
+            .map_err(|e| GeminiError::ParseError(format!("Deserialization error: {}", e)))?;
+        Ok(counted.total_tokens)
+    }
+
+    // Files API にアップロードしたファイルを削除する
+    // file_name は "files/abc123" と "abc123" のどちらでもよい
+    pub fn delete_file(&self, file_name: &str) -> Result<(), GeminiError> {
+        let name = file_name.strip_prefix("files/").unwrap_or(file_name);
+        let url = format!("{}/files/{}", self.base_url, name);
+        self.http.delete(&url, &self.api_key()?)?;
+        Ok(())
+    }
+
+    // クライアントを破棄して、接続プールに残っている keep-alive の接続を閉じる
+    // プールは ureq::Agent の中にあり、clone したクライアント (without_functions などで作ったものも含む)
+    // や with_agent に渡したエージェントと共有される。接続が閉じるのはそれらが全て破棄された時で、
+    // 単に drop するのと同じ。いつ閉じるかをコード上で明示したい時に使う
+    pub fn shutdown(self) {
+        drop(self);
+    }
+
+    // 低レベルなAPI呼び出し
+    // with_model_fallbacks を指定している時は、一時的なエラーやモデルが使えない時に次のモデルを試す
+    fn generate_content(&self, request: &GenerateContentRequest) -> Result<GenerateContentResponse, GeminiError> {
+        let body = self.request_body(request)?;
+        //println!("REQ: {}", &body);
+        if self.model_fallbacks.is_empty() {
+            return self.generate_content_with_model(&self.model, body);
+        }
+
+        let mut failures = Vec::new();
+        for model in std::iter::once(&self.model).chain(&self.model_fallbacks) {
+            match self.generate_content_with_model(model, body.clone()) {
+                Ok(response) => return Ok(response),
+                Err(e) if e.is_retryable() || e.is_model_unavailable() => {
+                    failures.push(format!("{}: {}", model, e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(GeminiError::ApiError(format!("All models failed ({})", failures.join("; "))))
+    }
+
+    fn generate_content_with_model(&self, model: &str, body: String) -> Result<GenerateContentResponse, GeminiError> {
+        let url = format!("{}/models/{}:generateContent", self.base_url, model);
+        //dbg!(&url);
+
+        if self.dry_run {
+            println!("[DRY RUN] POST {}", url);
+            println!("[DRY RUN] {}", body);
+            return Ok(GenerateContentResponse::dry_run_placeholder(body));
+        }
+
+        let cache_key = Self::cache_key(&url, &body);
+        if let Some(cache) = &self.cache {
+            if let Some(response) = cache.lock().unwrap().get(cache_key) {
+                return Ok(response);
+            }
+        }
+        
+        let response_body = match &self.transport {
+            Some(transport) => transport.post(&url, &self.api_key()?, &body)?,
+            None => self.http.post(&url, &self.api_key()?, &body)?,
+        };
+        //println!("RES: {}", &response_body);
+
+        let response: GenerateContentResponse = serde_json::from_str(&response_body)
+            .map_err(|e| Self::response_parse_error(&response_body, e))?;
+
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().insert(cache_key, response.clone());
+        }
         
-        if let Some(candidate) = response.candidates.first() {
-            if let Some(ResponsePart::Text { text }) = candidate.content.parts.first() {
-                return Ok(text.clone());
+        Ok(response)
+    }
+
+    // ストリーミングで送信し、SSE の各イベントを返す
+    // キャッシュは使われない
+    fn stream_content(&self, request: &GenerateContentRequest) -> Result<ResponseStream, GeminiError> {
+        let url = format!("{}/models/{}:streamGenerateContent?alt=sse", self.base_url, self.model);
+        let body = self.request_body(request)?;
+
+        if self.dry_run {
+            println!("[DRY RUN] POST {}", url);
+            println!("[DRY RUN] {}", body);
+            let placeholder = serde_json::json!({
+                "candidates": [{
+                    "content": { "parts": [{ "text": body }] },
+                    "finishReason": "DRY_RUN",
+                }],
+            });
+            let event = format!("data: {}\n\n", placeholder);
+            // メモリ上のデータなので待つ必要はない
+            return ResponseStream::new(Box::new(std::io::Cursor::new(event.into_bytes())), None);
+        }
+
+        let stream = match &self.transport {
+            Some(transport) => transport.post_stream(&url, &self.api_key()?, &body)?,
+            None => self.http.post_stream(&url, &self.api_key()?, &body)?,
+        };
+        ResponseStream::new(stream, self.stream_idle_timeout)
+    }
+
+    // インターセプタを適用し、検証してからシリアライズする
+    fn request_body(&self, request: &GenerateContentRequest) -> Result<String, GeminiError> {
+        let mut request = request.clone();
+        if let Some(language) = &self.default_language {
+            request.system_instruction.parts.insert(0, Part::Text {
+                text: format!("Always respond in {}.", language),
+            });
+        }
+        // v1 では systemInstruction にも role を付ける
+        if self.api_version == "v1" && request.system_instruction.role.is_none() {
+            request.system_instruction.role = Some("system".to_string());
+        }
+        if let Some(interceptor) = &self.request_interceptor {
+            interceptor(&mut request);
+        }
+
+        // 空の contents や parts は API が分かりにくい 400 を返すので、送る前に弾く
+        if request.contents.is_empty() {
+            return Err(GeminiError::ApiError("contents must not be empty".to_string()));
+        }
+        if request.contents.iter().any(|content| content.parts.is_empty()) {
+            return Err(GeminiError::ApiError("content parts must not be empty".to_string()));
+        }
+
+        for tool in request.tools.iter().flatten() {
+            for declaration in &tool.function_declarations {
+                declaration.validate()?;
+            }
+        }
+        check_duplicate_functions(
+            request.tools.iter().flatten().flat_map(|tool| &tool.function_declarations),
+        )?;
+        if let Some(labels) = &request.labels {
+            validate_labels(labels)?;
+        }
+
+        let body = serde_json::to_string(&request)
+            .map_err(|e| GeminiError::ParseError(format!("Serialization error: {}", e)))?;
+        if body.len() > self.max_request_bytes {
+            return Err(GeminiError::ApiError(format!(
+                "Request body is {} bytes, over the limit of {} bytes. Upload large files with the Files API or trim the conversation history",
+                body.len(),
+                self.max_request_bytes
+            )));
+        }
+        Ok(body)
+    }
+
+    // 読み込みの途中で接続が切れてボディが途切れた時は、パースの失敗ではなく再試行できる NetworkError にする
+    fn response_parse_error(body: &str, e: serde_json::Error) -> GeminiError {
+        if e.is_eof() && !body.trim().is_empty() {
+            GeminiError::NetworkError(format!("Response body was truncated ({} bytes): {}", body.len(), e))
+        } else {
+            GeminiError::ParseError(format!("Deserialization error: {}", e))
+        }
+    }
+
+    // URL (モデル名を含む) とシリアライズ済みのボディからキャッシュのキーを作る
+    fn cache_key(url: &str, body: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        body.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+// 最近使った順に capacity 件までレスポンスを持つキャッシュ
+struct ResponseCache {
+    capacity: usize,
+    entries: HashMap<u64, GenerateContentResponse>,
+    // 前ほど長く使われていないキー
+    order: VecDeque<u64>,
+}
+
+impl ResponseCache {
+    fn new(capacity: usize) -> Self {
+        ResponseCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, key: u64) -> Option<GenerateContentResponse> {
+        let response = self.entries.get(&key)?.clone();
+        self.touch(key);
+        Some(response)
+    }
+
+    fn insert(&mut self, key: u64, response: GenerateContentResponse) {
+        if self.entries.insert(key, response).is_some() {
+            self.touch(key);
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn touch(&mut self, key: u64) {
+        self.order.retain(|k| *k != key);
+        self.order.push_back(key);
+    }
+}
+
+// SSE の各イベントを GenerateContentResponse として返すイテレータ
+// idle_timeout がある時は読み込みを別スレッドで行い、その間に次の行が届かなければ Timeout を返す
+// タイムアウトした後は何も返さない (読み込みのスレッドは接続が切れるまで残る)
+pub struct ResponseStream {
+    lines: StreamLines,
+    timed_out: bool,
+}
+
+enum StreamLines {
+    // idle_timeout が無い時はスレッドを使わずにその場で読む
+    Inline(std::io::Lines<BufReader<Box<dyn Read + Send>>>),
+    Thread {
+        receiver: Receiver<std::io::Result<String>>,
+        idle_timeout: Duration,
+    },
+}
+
+impl ResponseStream {
+    // スレッドが使えない環境 (wasm32-wasi など) では、idle_timeout を None にする必要がある
+    fn new(reader: Box<dyn Read + Send>, idle_timeout: Option<Duration>) -> Result<Self, GeminiError> {
+        let lines = match idle_timeout {
+            None => StreamLines::Inline(BufReader::new(reader).lines()),
+            Some(idle_timeout) => {
+                let (sender, receiver) = mpsc::channel();
+                thread::Builder::new()
+                    .name("gemini-stream-reader".to_string())
+                    .spawn(move || {
+                        for line in BufReader::new(reader).lines() {
+                            if sender.send(line).is_err() {
+                                break;
+                            }
+                        }
+                    })
+                    .map_err(|e| GeminiError::ApiError(format!(
+                        "Failed to start the stream reader thread ({}); use with_stream_idle_timeout(None) where threads are unavailable",
+                        e
+                    )))?;
+                StreamLines::Thread { receiver, idle_timeout }
+            }
+        };
+        Ok(ResponseStream {
+            lines,
+            timed_out: false,
+        })
+    }
+
+    fn next_line(&mut self) -> Option<Result<String, GeminiError>> {
+        if self.timed_out {
+            return None;
+        }
+        let (receiver, idle_timeout) = match &mut self.lines {
+            StreamLines::Inline(lines) => {
+                return lines.next().map(|line| {
+                    line.map_err(|e| GeminiError::NetworkError(format!("Stream read failed: {}", e)))
+                });
+            }
+            StreamLines::Thread { receiver, idle_timeout } => (receiver, *idle_timeout),
+        };
+        match receiver.recv_timeout(idle_timeout) {
+            Ok(Ok(line)) => Some(Ok(line)),
+            Ok(Err(e)) => Some(Err(GeminiError::NetworkError(format!("Stream read failed: {}", e)))),
+            Err(RecvTimeoutError::Timeout) => {
+                self.timed_out = true;
+                Some(Err(GeminiError::Timeout(format!(
+                    "No stream data received for {:?}",
+                    idle_timeout
+                ))))
+            }
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl Iterator for ResponseStream {
+    type Item = Result<GenerateContentResponse, GeminiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.next_line()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+            // "data: {...}" 以外の行 (空行やコメント) は読み飛ばす
+            let Some(data) = line.strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data.is_empty() {
+                continue;
+            }
+            return Some(
+                serde_json::from_str(data)
+                    .map_err(|e| GeminiError::ParseError(format!("Deserialization error: {}", e))),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    Text(String),
+    // thought_signature は次のターンで関数呼び出しと一緒に送り返す
+    FunctionCall { call: FunctionCall, thought_signature: Option<String> },
+    // それまでに使ったトークン数 (累計)。トークン数のライブ表示などに使う
+    Usage(UsageMetadata),
+}
+
+// ストリーミング中のテキストと使用量を順に返し、関数呼び出しは最後にまとめて返すイテレータ
+pub struct StreamEvents {
+    chunks: ResponseStream,
+    pending: VecDeque<StreamEvent>,
+    function_calls: Vec<StreamEvent>,
+    finished: bool,
+}
+
+impl StreamEvents {
+    fn new(chunks: ResponseStream) -> Self {
+        StreamEvents {
+            chunks,
+            pending: VecDeque::new(),
+            function_calls: vec![],
+            finished: false,
+        }
+    }
+}
+
+impl Iterator for StreamEvents {
+    type Item = Result<StreamEvent, GeminiError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.finished {
+                return None;
+            }
+
+            match self.chunks.next() {
+                Some(Ok(chunk)) => {
+                    let usage = chunk.usage_metadata;
+                    let parts = chunk.candidates.into_iter()
+                        .next()
+                        .and_then(|candidate| candidate.content)
+                        .map(|content| content.parts)
+                        .unwrap_or_default();
+                    for part in parts {
+                        match part {
+                            ResponsePart::Text { text } => self.pending.push_back(StreamEvent::Text(text)),
+                            ResponsePart::FunctionCall { function_call, thought_signature } => {
+                                self.function_calls.push(StreamEvent::FunctionCall {
+                                    call: function_call,
+                                    thought_signature,
+                                });
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Some(usage) = usage {
+                        self.pending.push_back(StreamEvent::Usage(usage));
+                    }
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.finished = true;
+                    self.pending.extend(self.function_calls.drain(..));
+                }
             }
         }
-        
-        Err(GeminiError::ApiError("No text response found".to_string()))
     }
-    
-    // Function Callingを使った生成
-    pub fn generate_with_functions(
-        &self, 
-        prompt: &str, 
-    ) -> Result<GenerateContentResponse, GeminiError> {
-        let request = GenerateContentRequest {
-            system_instruction: self.system_instruction.clone(),
-            contents: vec![Content {
-                role: "user".to_string(),
-                parts: vec![Part::Text {
-                    text: prompt.to_string(),
-                }],
-            }],
-            tools: Some(vec![Tool {
-                function_declarations: self.functions.clone(),
-            }]),
-        };
-        
-        self.generate_content(&request)
+}
+
+// デバッグ用に会話を一行ずつ表示できる形にする
+//   [user] テキスト
+//   [model] -> call: name(args)
+//   [user] <- result: name = value
+pub fn format_conversation(contents: &[Content]) -> String {
+    let mut lines = Vec::new();
+    for content in contents {
+        for part in &content.parts {
+            let line = match part {
+                Part::Text { text } => text.clone(),
+                Part::FunctionCall { function_call, .. } => {
+                    format!("-> call: {}({})", function_call.name, function_call.args)
+                }
+                Part::FunctionResponse { function_response } => {
+                    format!("<- result: {} = {}", function_response.name, function_response.response)
+                }
+                Part::InlineData { inline_data } => {
+                    format!("<inline data: {}, {} base64 chars>", inline_data.mime_type, inline_data.data.len())
+                }
+                Part::ExecutableCode { executable_code } => {
+                    format!("code ({}): {}", executable_code.language, executable_code.code)
+                }
+                Part::CodeExecutionResult { code_execution_result } => format!(
+                    "code result ({}): {}",
+                    code_execution_result.outcome,
+                    code_execution_result.output.as_deref().unwrap_or("")
+                ),
+            };
+            lines.push(format!("[{}] {}", content.role, line));
+        }
     }
-    
-    // Function Callの結果を送信
-    pub fn continue_with_function_result(
-        &self,
-        conversation: &mut Vec<Content>,
-        function_name: &str,
-        result: serde_json::Value,
-    ) -> Result<GenerateContentResponse, GeminiError> {
-        conversation.push(Content {
-            role: "user".to_string(),
-            parts: vec![Part::FunctionResponse {
-                function_response: FunctionResponse {
-                    name: function_name.to_string(),
-                    response: result,
-                },
-            }],
-        });
-        
-        let request = GenerateContentRequest {
-            system_instruction: self.system_instruction.clone(),
-            contents: conversation.clone(),
-            tools: Some(vec![Tool {
-                function_declarations: self.functions.clone(),
-            }]),
-        };
-        
-        self.generate_content(&request)
+    lines.join("\n")
+}
+
+// run_agent の結果
+#[derive(Debug, Clone)]
+pub struct AgentResult {
+    pub text: String,
+    // モデルへのリクエスト回数
+    pub turns_used: usize,
+    pub function_calls_made: usize,
+    // 最後の応答が MAX_TOKENS で途切れている
+    pub truncated: bool,
+}
+
+// テキスト生成のバックエンドを差し替えるためのトレイト (テスト用のモックやローカルLLMなど)
+pub trait TextGenerator {
+    fn generate_text(&self, prompt: &str) -> Result<String, GeminiError>;
+}
+
+impl TextGenerator for GeminiClient {
+    fn generate_text(&self, prompt: &str) -> Result<String, GeminiError> {
+        GeminiClient::generate_text(self, prompt)
     }
-    
-    // 低レベルなAPI呼び出し
-    fn generate_content(&self, request: &GenerateContentRequest) -> Result<GenerateContentResponse, GeminiError> {
-        let url = format!("{}/models/gemini-1.5-flash:generateContent", self.base_url);
-        //dbg!(&url);
+}
 
-        let body = serde_json::to_string(request)
-            .map_err(|e| GeminiError::ParseError(format!("Serialization error: {}", e)))?;
-        //println!("REQ: {}", &body);
-        
-        let response_body = SimpleHttpClient::post(&url, self.api_key.clone(), &body)?;
-        //println!("RES: {}", &response_body);
+// アップロード済みファイルへの参照
+// delete_on_drop を指定すると、スコープを抜けた時にサーバー上のファイルを削除する
+// 注意:
+// - Drop ではエラーを返せないので、削除に失敗しても無視される。確実に消したい時は delete() を呼ぶ
+// - パニックによる巻き戻し中にも Drop は走り、そこで通信が発生する
+// - abort や std::process::exit、mem::forget の場合は削除されない
+pub struct FileRef<'a> {
+    client: &'a GeminiClient,
+    name: String,
+    delete_on_drop: bool,
+}
 
-        let response: GenerateContentResponse = serde_json::from_str(&response_body)
-            .map_err(|e| GeminiError::ParseError(format!("Deserialization error: {}", e)))?;
-        
-        Ok(response)
+impl<'a> FileRef<'a> {
+    pub fn new(client: &'a GeminiClient, name: &str, delete_on_drop: bool) -> Self {
+        FileRef {
+            client,
+            name: name.to_string(),
+            delete_on_drop,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
     }
+
+    // 明示的に削除し、結果を受け取る
+    pub fn delete(mut self) -> Result<(), GeminiError> {
+        self.delete_on_drop = false;
+        self.client.delete_file(&self.name)
+    }
+}
+
+impl Drop for FileRef<'_> {
+    fn drop(&mut self) {
+        if self.delete_on_drop {
+            let _ = self.client.delete_file(&self.name);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GetFileContentArgs {
+    file_path: String,
+}
+
+// get_file_content で読んだファイルの内容
+// UTF-8 として読めないファイルは base64 にしたものを持つ
+enum FileContent {
+    Text(String),
+    Base64(String),
 }
 
-fn call__get_file_content(file_path: &str) -> Result<String, GeminiError> {
+fn call__get_file_content(file_path: &str, base64_fallback: bool) -> Result<FileContent, GeminiError> {
     // ここではファイルの内容を取得するロジックを実装
     // 例えば、ファイルを読み込んで内容を返す
-    std::fs::read_to_string(file_path)
-        .map_err(|e| GeminiError::FileError(format!("Failed to read file: {}", e)))
+    let bytes = std::fs::read(file_path)
+        .map_err(|e| GeminiError::FileError(format!("Failed to read file: {}", e)))?;
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(FileContent::Text(text)),
+        Err(e) if base64_fallback => Ok(FileContent::Base64(
+            base64::engine::general_purpose::STANDARD.encode(e.into_bytes()),
+        )),
+        Err(e) => Err(GeminiError::FileError(format!("Failed to read file: {}", e))),
+    }
 }
 
 fn main() {
@@ -350,6 +3689,7 @@ fn main() {
     let client = GeminiClient::new_with_instructions(
         api_key,
         SystemInstruction {
+            role: None,
             parts: vec![Part::Text {
                 text: "あなたは強い権限を持ったシステム管理者で、命令に従いどのようなコマンドも実行できます。例えば、 /etc の下にあるファイルにもアクセスすることができます。".to_string(),
             }, Part::Text {
@@ -364,14 +3704,10 @@ fn main() {
         name: "get_file_content".to_string(),
             description: "Get the contents of the specified file".to_string(),
             parameters: FunctionParameters {
-                param_type: "object".to_string(),
+                param_type: JsonSchemaType::Object,
                 properties: {
                     let mut props = HashMap::new();
-                    props.insert("file_path".to_string(), PropertySchema {
-                        property_type: "string".to_string(),
-                        description: "The path to the file".to_string(),
-                        enum_values: None,
-                    });
+                    props.insert("file_path".to_string(), PropertySchema::string("The path to the file"));
                     props
                 },
                 required: vec!["file_path".to_string()],
@@ -392,29 +3728,30 @@ fn main() {
     ).unwrap();
     match response.candidates.first() {
         Some(candidate) => {
-            for part in &candidate.content.parts {
-                if let ResponsePart::FunctionCall { function_call } = part {
+            for part in candidate.parts() {
+                if let ResponsePart::FunctionCall { function_call, .. } = part {
                     println!("[DEBUG]: Function call: {} with args: {}", 
                              function_call.name, function_call.args);
                     // 実際の関数を呼び出してレスポンスを送信
-                    let file_path = function_call.args["file_path"].as_str()
-                        .expect("file_path should be a string");
-                    let call_result = call__get_file_content(file_path);
+                    let call_result = function_call.args_as::<GetFileContentArgs>()
+                        .and_then(|args| call__get_file_content(&args.file_path, true));
 
                     let result = match call_result {
-                        Ok(s) => {
+                        Ok(FileContent::Text(s)) => {
                             // println!("File content: {}", s);
-                            serde_json::json!({
-                                "result": "success",
+                            FunctionResponse::success(&function_call.name, serde_json::json!({
                                 "file_content": s.trim(),
-                            })
+                            }))
+                        },
+                        Ok(FileContent::Base64(data)) => {
+                            FunctionResponse::success(&function_call.name, serde_json::json!({
+                                "file_content": data,
+                                "encoding": "base64",
+                            }))
                         },
                         Err(e) => {
                             eprintln!("Error reading file: {}", e);
-                            serde_json::json!({
-                                "result": "failure",
-                                "error": e.to_string(),
-                            })
+                            FunctionResponse::error(&function_call.name, &e.to_string())
                         }
                     };
                     let mut conversation = vec![
@@ -426,9 +3763,7 @@ fn main() {
                         },
                         Content {
                             role: "model".to_string(),
-                            parts: vec![Part::FunctionCall {
-                                function_call: function_call.clone(),
-                            }],
+                            parts: vec![part.to_request_part()],
                         }
                     ];
                     
@@ -437,10 +3772,10 @@ fn main() {
                     let final_response = client.continue_with_function_result(
                         &mut conversation,
                         &function_call.name,
-                        result,
+                        result.response,
                     ).unwrap();
 
-                    for part in &final_response.candidates[0].content.parts {
+                    for part in final_response.candidates[0].parts() {
                         if let ResponsePart::Text { text } = part {
                             println!("Response: {}", text);
                         }
@@ -451,3 +3786,715 @@ fn main() {
         None => println!("No candidates found in response"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 決められたレスポンスを順番に返し、送られたリクエストを記録する
+    struct FakeTransport {
+        responses: Mutex<VecDeque<Result<String, GeminiError>>>,
+        // ストリーミングのレスポンス (ステータス, content-type, ボディ)
+        streams: Mutex<VecDeque<(u16, &'static str, String)>>,
+        requests: Arc<Mutex<Vec<(String, String)>>>,
+        http: SimpleHttpClient,
+    }
+
+    impl FakeTransport {
+        fn new(responses: &[&str]) -> Self {
+            FakeTransport {
+                responses: Mutex::new(responses.iter().map(|r| Ok(r.to_string())).collect()),
+                streams: Mutex::new(VecDeque::new()),
+                requests: Arc::new(Mutex::new(vec![])),
+                http: SimpleHttpClient::new(),
+            }
+        }
+
+        fn with_stream(self, status: u16, content_type: &'static str, body: &str) -> Self {
+            self.streams.lock().unwrap().push_back((status, content_type, body.to_string()));
+            self
+        }
+
+        // 次のレスポンスの代わりにエラーを返す
+        fn with_error(self, error: GeminiError) -> Self {
+            self.responses.lock().unwrap().push_back(Err(error));
+            self
+        }
+
+        fn next(&self, url: &str, body: &str) -> Result<String, GeminiError> {
+            self.requests.lock().unwrap().push((url.to_string(), body.to_string()));
+            self.responses.lock().unwrap().pop_front()
+                .unwrap_or_else(|| Err(GeminiError::NetworkError("no more canned responses".to_string())))
+        }
+    }
+
+    impl HttpTransport for FakeTransport {
+        fn post(&self, url: &str, _api_key: &str, body: &str) -> Result<String, GeminiError> {
+            self.next(url, body)
+        }
+
+        fn get(&self, url: &str, _api_key: &str) -> Result<String, GeminiError> {
+            self.next(url, "")
+        }
+
+        // 本物の HTTP レスポンスと同じく、ステータスと content-type の確認を通す
+        fn post_stream(&self, url: &str, _api_key: &str, body: &str) -> Result<Box<dyn Read + Send>, GeminiError> {
+            self.requests.lock().unwrap().push((url.to_string(), body.to_string()));
+            let (status, content_type, body) = self.streams.lock().unwrap().pop_front()
+                .ok_or_else(|| GeminiError::NetworkError("no more canned streams".to_string()))?;
+            let headers = HashMap::from([("content-type".to_string(), content_type.to_string())]);
+            self.http.stream_body(url, status, headers, Box::new(std::io::Cursor::new(body.into_bytes())))
+        }
+    }
+
+    fn client_with(transport: FakeTransport) -> GeminiClient {
+        GeminiClient::with_api_key("test-key".to_string()).with_transport(transport)
+    }
+
+    fn declaration(name: &str) -> FunctionDeclaration {
+        FunctionDeclaration {
+            name: name.to_string(),
+            description: format!("test function {}", name),
+            parameters: FunctionParameters {
+                param_type: JsonSchemaType::Object,
+                properties: HashMap::new(),
+                required: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn list_models_follows_next_page_token() {
+        let transport = FakeTransport::new(&[
+            r#"{"models":[{"name":"models/a"}],"nextPageToken":"page 2"}"#,
+            r#"{"models":[{"name":"models/b"}]}"#,
+        ]);
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+
+        let models = client.list_models().unwrap();
+        let names: Vec<&str> = models.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["models/a", "models/b"]);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].0.contains("pageToken"));
+        assert!(requests[1].0.ends_with("&pageToken=page%202"));
+    }
+
+    #[test]
+    fn generate_text_skips_blocked_first_candidate() {
+        let client = client_with(FakeTransport::new(&[r#"{"candidates":[
+            {"finishReason":"SAFETY"},
+            {"content":{"parts":[
+                {"inlineData":{"mimeType":"audio/wav","data":"AAAA"}},
+                {"text":"hello"}
+            ]},"finishReason":"STOP"}
+        ]}"#]));
+
+        assert_eq!(client.generate_text("hi").unwrap(), "hello");
+    }
+
+    #[test]
+    fn content_less_safety_candidate_reports_finish_reason() {
+        let response: GenerateContentResponse =
+            serde_json::from_str(r#"{"candidates":[{"finishReason":"SAFETY"}]}"#).unwrap();
+        assert!(response.candidates[0].content.is_none());
+
+        let client = client_with(FakeTransport::new(&[r#"{"candidates":[{"finishReason":"SAFETY"}]}"#]));
+        let err = client.generate_text("hi").unwrap_err();
+        assert!(matches!(err, GeminiError::Blocked(_)));
+        assert!(err.to_string().contains("Safety"), "{}", err);
+    }
+
+    #[test]
+    fn ask_reports_blocked_answer_like_generate_text() {
+        let blocked = r#"{"candidates":[{"finishReason":"SAFETY"}]}"#;
+        let client = client_with(FakeTransport::new(&[blocked, blocked]));
+
+        let asked = client.ask("hi").unwrap_err();
+        let generated = client.generate_text("hi").unwrap_err();
+        assert!(matches!(asked, GeminiError::Blocked(_)), "{:?}", asked);
+        assert_eq!(asked.to_string(), generated.to_string());
+    }
+
+    #[test]
+    fn replay_matches_bodies_regardless_of_key_order() {
+        let recorded = r#"{"contents":[],"tools":[{"parameters":{"a":{"type":"string"},"b":{"type":"number"}}}]}"#;
+        let replayed = r#"{"tools":[{"parameters":{"b":{"type":"number"},"a":{"type":"string"}}}],"contents":[]}"#;
+        assert_eq!(ReplayTransport::body_hash(recorded), ReplayTransport::body_hash(replayed));
+        assert_ne!(ReplayTransport::body_hash(recorded), ReplayTransport::body_hash(r#"{"contents":[{}]}"#));
+    }
+
+    #[test]
+    fn truncated_body_is_retryable_network_error() {
+        let client = client_with(FakeTransport::new(&[r#"{"candidates":["#]));
+        let err = client.generate_text("hi").unwrap_err();
+        assert!(matches!(err, GeminiError::NetworkError(_)), "{:?}", err);
+        assert!(err.is_retryable());
+
+        let client = client_with(FakeTransport::new(&[r#"{"candidates":1}"#]));
+        assert!(matches!(client.generate_text("hi").unwrap_err(), GeminiError::ParseError(_)));
+    }
+
+    #[test]
+    fn content_builder_serializes_text_and_image() {
+        let content = Content::builder(Role::User)
+            .text("What is this?")
+            .image("image/png", b"\x89PNG")
+            .build();
+
+        assert_eq!(serde_json::to_value(&content).unwrap(), serde_json::json!({
+            "role": "user",
+            "parts": [
+                { "text": "What is this?" },
+                { "inlineData": { "mimeType": "image/png", "data": "iVBORw==" } },
+            ],
+        }));
+    }
+
+    #[test]
+    fn run_agent_passes_text_next_to_function_call_to_reasoning_handler() {
+        let reasoning = Arc::new(Mutex::new(vec![]));
+        let seen = reasoning.clone();
+        let mut client = client_with(FakeTransport::new(&[
+            r#"{"candidates":[{"content":{"role":"model","parts":[
+                {"text":"I should look up the weather."},
+                {"functionCall":{"name":"weather","args":{}}}
+            ]},"finishReason":"STOP"}]}"#,
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"It is sunny."}]},"finishReason":"STOP"}]}"#,
+        ]))
+        .with_reasoning_handler(move |text| seen.lock().unwrap().push(text.to_string()));
+        client.register_function(declaration("weather"), |_| Ok(serde_json::json!({ "sky": "sunny" })));
+
+        let result = client.run_agent("weather?", 5).unwrap();
+        assert_eq!(result.text, "It is sunny.");
+        assert_eq!(result.function_calls_made, 1);
+        assert_eq!(*reasoning.lock().unwrap(), ["I should look up the weather."]);
+    }
+
+    #[test]
+    fn run_agent_interleaves_narration_and_calls_in_part_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let transport = FakeTransport::new(&[
+            r#"{"candidates":[{"content":{"role":"model","parts":[
+                {"text":"first"},
+                {"functionCall":{"name":"a","args":{}}},
+                {"text":"second"},
+                {"functionCall":{"name":"b","args":{}}}
+            ]},"finishReason":"STOP"}]}"#,
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"done"}]},"finishReason":"STOP"}]}"#,
+        ]);
+        let requests = transport.requests.clone();
+        let narration = log.clone();
+        let mut client = client_with(transport)
+            .with_reasoning_handler(move |text| narration.lock().unwrap().push(format!("text:{}", text)));
+        for name in ["a", "b"] {
+            let calls = log.clone();
+            client.register_function(declaration(name), move |_| {
+                calls.lock().unwrap().push(format!("call:{}", name));
+                Ok(serde_json::json!({}))
+            });
+        }
+
+        client.run_agent("go", 5).unwrap();
+        assert_eq!(*log.lock().unwrap(), ["text:first", "call:a", "text:second", "call:b"]);
+
+        // 関数の結果も呼び出しの順に返す
+        let second: Value = serde_json::from_str(&requests.lock().unwrap()[1].1).unwrap();
+        let responses = &second["contents"][2]["parts"];
+        assert_eq!(responses[0]["functionResponse"]["name"], "a");
+        assert_eq!(responses[1]["functionResponse"]["name"], "b");
+    }
+
+    #[test]
+    fn run_agent_retries_malformed_function_call_once() {
+        let malformed = r#"{"candidates":[{"finishReason":"MALFORMED_FUNCTION_CALL"}]}"#;
+        let transport = FakeTransport::new(&[malformed, malformed]);
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+
+        let err = client.run_agent("go", 5).unwrap_err();
+        assert!(matches!(err, GeminiError::ApiError(_)), "{:?}", err);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let retry: Value = serde_json::from_str(&requests[1].1).unwrap();
+        let hint = retry["contents"].as_array().unwrap().last().unwrap();
+        assert_eq!(hint["role"], "user");
+        assert!(hint["parts"][0]["text"].as_str().unwrap().contains("malformed"));
+    }
+
+    #[test]
+    fn model_404_is_model_not_found() {
+        let url = "https://generativelanguage.googleapis.com/v1beta/models/gemini-x:generateContent";
+        let err = SimpleHttpClient::status_error(url, 404, r#"{"error":{"message":"not found"}}"#, HashMap::new());
+        assert!(matches!(&err, GeminiError::ModelNotFound(model) if model == "gemini-x"), "{:?}", err);
+        assert!(err.is_model_unavailable());
+        assert!(!GeminiError::ApiError("model 'gemini-x' not found".to_string()).is_model_unavailable());
+    }
+
+    #[test]
+    fn rate_limit_error_keeps_response_headers() {
+        let url = "https://generativelanguage.googleapis.com/v1beta/models/gemini-x:generateContent";
+        let body = r#"{"error":{"code":429,"message":"Quota exceeded","status":"RESOURCE_EXHAUSTED"}}"#;
+        let headers = HashMap::from([
+            ("retry-after".to_string(), "30".to_string()),
+            ("x-ratelimit-remaining".to_string(), "0".to_string()),
+        ]);
+
+        match SimpleHttpClient::status_error(url, 429, body, headers) {
+            GeminiError::HttpStatus { code, status, headers, .. } => {
+                assert_eq!(code, 429);
+                assert_eq!(status.as_deref(), Some("RESOURCE_EXHAUSTED"));
+                assert_eq!(headers["retry-after"], "30");
+                assert_eq!(headers["x-ratelimit-remaining"], "0");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn name_lookup_failures_suggest_resolved_ip() {
+        let url = "https://generativelanguage.googleapis.com/v1beta/models";
+        let errors = [
+            ureq::Error::HostNotFound,
+            ureq::Error::Timeout(ureq::Timeout::Resolve),
+            ureq::Error::Io(std::io::Error::other("failed to lookup address information: Name or service not known")),
+            ureq::Error::Io(std::io::Error::new(std::io::ErrorKind::Unsupported, "operation not supported on this platform")),
+        ];
+        for e in errors {
+            let err = SimpleHttpClient::request_error(url, e);
+            assert!(err.to_string().contains("with_resolved_ip(BASE_IP)"), "{}", err);
+        }
+
+        let err = SimpleHttpClient::request_error(url, ureq::Error::ConnectionFailed);
+        assert!(err.to_string().contains("Request failed"), "{}", err);
+    }
+
+    #[test]
+    fn validate_key_maps_rejected_key_to_auth_error() {
+        for code in [401, 403] {
+            let client = client_with(FakeTransport::new(&[]).with_error(GeminiError::HttpStatus {
+                code,
+                message: "API key not valid".to_string(),
+                status: Some("PERMISSION_DENIED".to_string()),
+                details: vec![],
+                headers: HashMap::new(),
+            }));
+            let err = client.validate_key().unwrap_err();
+            assert!(matches!(err, GeminiError::AuthError(_)), "{:?}", err);
+        }
+
+        let client = client_with(FakeTransport::new(&[]).with_error(invalid_key_error()));
+        assert!(matches!(client.validate_key().unwrap_err(), GeminiError::AuthError(_)));
+
+        // API_KEY_INVALID でない 400 はそのまま返す
+        let client = client_with(FakeTransport::new(&[]).with_error(GeminiError::HttpStatus {
+            code: 400,
+            message: "Invalid page size".to_string(),
+            status: Some("INVALID_ARGUMENT".to_string()),
+            details: vec![],
+            headers: HashMap::new(),
+        }));
+        assert!(matches!(client.validate_key().unwrap_err(), GeminiError::HttpStatus { code: 400, .. }));
+
+        let client = client_with(FakeTransport::new(&[r#"{"models":[]}"#]));
+        assert!(client.validate_key().is_ok());
+    }
+
+    fn invalid_key_error() -> GeminiError {
+        GeminiError::HttpStatus {
+            code: 400,
+            message: "API key not valid. Please pass a valid API key.".to_string(),
+            status: Some("INVALID_ARGUMENT".to_string()),
+            details: vec![serde_json::json!({
+                "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                "reason": "API_KEY_INVALID",
+                "domain": "googleapis.com",
+            })],
+            headers: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn generate_batch_skips_remaining_prompts_after_invalid_key() {
+        let transport = FakeTransport::new(&[]).with_error(invalid_key_error());
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+
+        let results = client.generate_batch(&["a", "b", "c"], 1);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| matches!(result, Err(GeminiError::AuthError(_)))));
+        assert!(results[2].as_ref().unwrap_err().to_string().contains("skipped"));
+    }
+
+    #[test]
+    fn audio_limit_is_checked_against_base64_length() {
+        // 15MB を少し超えると、base64 にした時に 20MB を超える
+        let audio = vec![0u8; MAX_INLINE_AUDIO_BYTES / 4 * 3 + 1];
+        let client = client_with(FakeTransport::new(&[]));
+        let err = client.generate_with_audio("transcribe", &audio, "audio/wav").unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+    }
+
+    #[test]
+    fn saved_conversation_loads_back_into_run_agent_continue() {
+        let conversation = vec![
+            Content::builder(Role::User).text("weather?").image("image/png", b"png").build(),
+            Content {
+                role: "model".to_string(),
+                parts: vec![Part::FunctionCall {
+                    function_call: FunctionCall {
+                        name: "weather".to_string(),
+                        args: serde_json::json!({ "city": "Tokyo" }),
+                    },
+                    thought_signature: Some("sig".to_string()),
+                }],
+            },
+            Content::function_result("weather", serde_json::json!({ "sky": "sunny" })),
+        ];
+        let saved = serde_json::to_string(&conversation).unwrap();
+        let mut loaded: Conversation = serde_json::from_str(&saved).unwrap();
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), saved);
+
+        let instruction: SystemInstruction =
+            serde_json::from_str(r#"{"parts":[{"text":"be brief"}]}"#).unwrap();
+        assert!(instruction.role.is_none());
+
+        let transport = FakeTransport::new(&[
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"It is sunny."}]},"finishReason":"STOP"}]}"#,
+        ]);
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+        let result = client.run_agent_continue(&mut loaded, 5).unwrap();
+        assert_eq!(result.text, "It is sunny.");
+        assert_eq!(loaded.len(), 4);
+
+        let sent: Value = serde_json::from_str(&requests.lock().unwrap()[0].1).unwrap();
+        assert_eq!(sent["contents"][1]["parts"][0]["thoughtSignature"], "sig");
+    }
+
+    #[test]
+    fn blocked_turn_is_not_added_to_the_conversation() {
+        let blocked = r#"{"candidates":[{"finishReason":"SAFETY"}]}"#;
+        let client = client_with(FakeTransport::new(&[blocked, blocked]));
+
+        let mut conversation = vec![Content::builder(Role::User).text("hi").build()];
+        let err = client.run_agent_continue(&mut conversation, 5).unwrap_err();
+        assert!(matches!(err, GeminiError::Blocked(_)), "{:?}", err);
+        assert_eq!(conversation.len(), 1);
+
+        let err = client.continue_generation(&mut conversation).unwrap_err();
+        assert!(matches!(err, GeminiError::Blocked(_)), "{:?}", err);
+        assert!(conversation.iter().all(|content| !content.parts.is_empty()));
+    }
+
+    #[test]
+    fn respond_to_function_call_echoes_thought_signature() {
+        let part: ResponsePart = serde_json::from_str(
+            r#"{"functionCall":{"name":"weather","args":{}},"thoughtSignature":"sig"}"#,
+        ).unwrap();
+        let transport = FakeTransport::new(&[
+            r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"sunny"}]},"finishReason":"STOP"}]}"#,
+        ]);
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+
+        let mut history = vec![Content::builder(Role::User).text("weather?").build()];
+        client.respond_to_function_call(&mut history, &part, serde_json::json!({ "sky": "sunny" })).unwrap();
+
+        let sent: Value = serde_json::from_str(&requests.lock().unwrap()[0].1).unwrap();
+        assert_eq!(sent["contents"][1]["role"], "model");
+        assert_eq!(sent["contents"][1]["parts"][0]["thoughtSignature"], "sig");
+        assert_eq!(sent["contents"][2]["parts"][0]["functionResponse"]["name"], "weather");
+
+        let text = ResponsePart::Text { text: "hi".to_string() };
+        assert!(client.respond_to_function_call(&mut history, &text, Value::Null).is_err());
+    }
+
+    #[test]
+    fn stream_events_keep_thought_signature() {
+        let sse = concat!(
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Checking\"}]}}]}\n\n",
+            "data: {\"candidates\":[{\"content\":{\"parts\":[",
+            "{\"functionCall\":{\"name\":\"weather\",\"args\":{}},\"thoughtSignature\":\"sig\"}",
+            "]}}]}\n\n",
+        );
+        let chunks = ResponseStream::new(Box::new(std::io::Cursor::new(sse.as_bytes().to_vec())), None).unwrap();
+        let events: Vec<StreamEvent> = StreamEvents::new(chunks).map(Result::unwrap).collect();
+
+        assert!(matches!(&events[0], StreamEvent::Text(text) if text == "Checking"));
+        match &events[1] {
+            StreamEvent::FunctionCall { call, thought_signature } => {
+                assert_eq!(call.name, "weather");
+                assert_eq!(thought_signature.as_deref(), Some("sig"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn stream_error_parses_api_error_body() {
+        let url = "https://generativelanguage.googleapis.com/v1beta/models/gemini-x:streamGenerateContent?alt=sse";
+        let body = r#"{"error":{"code":400,"message":"Invalid JSON payload","status":"INVALID_ARGUMENT"}}"#;
+
+        match SimpleHttpClient::stream_error(url, 400, body, HashMap::new()) {
+            GeminiError::HttpStatus { code, message, status, .. } => {
+                assert_eq!(code, 400);
+                assert_eq!(message, "Invalid JSON payload");
+                assert_eq!(status.as_deref(), Some("INVALID_ARGUMENT"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        // 2xx でも SSE ではなくエラーのJSONが返ってきた場合
+        let err = SimpleHttpClient::stream_error(url, 200, body, HashMap::new());
+        assert!(matches!(&err, GeminiError::ApiError(message) if message == "Invalid JSON payload"), "{:?}", err);
+        let err = SimpleHttpClient::stream_error(url, 200, "<html>", HashMap::new());
+        assert!(err.to_string().contains("Expected an event stream"), "{}", err);
+    }
+
+    // 最初の read で止まったままになる接続の代わり
+    struct StalledReader(Duration);
+
+    impl Read for StalledReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(self.0);
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn response_stream_reads_inline_or_times_out() {
+        let sse = "data: {\"candidates\":[]}\n\n";
+        let inline = ResponseStream::new(Box::new(std::io::Cursor::new(sse.as_bytes().to_vec())), None).unwrap();
+        assert!(matches!(inline.lines, StreamLines::Inline(_)));
+        assert_eq!(inline.count(), 1);
+
+        let timeout = Some(Duration::from_secs(5));
+        let threaded = ResponseStream::new(Box::new(std::io::Cursor::new(sse.as_bytes().to_vec())), timeout).unwrap();
+        assert_eq!(threaded.count(), 1);
+
+        let stalled = Box::new(StalledReader(Duration::from_millis(500)));
+        let mut stream = ResponseStream::new(stalled, Some(Duration::from_millis(10))).unwrap();
+        assert!(matches!(stream.next(), Some(Err(GeminiError::Timeout(_)))));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn generate_batch_returns_a_result_per_prompt() {
+        let ok = r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"ok"}]},"finishReason":"STOP"}]}"#;
+        let client = client_with(FakeTransport::new(&[ok, ok, ok]));
+
+        let results = client.generate_batch(&["a", "b", "c"], 2);
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|result| result.as_deref().ok() == Some("ok")));
+    }
+
+    #[test]
+    fn resolved_ip_keeps_the_real_host() {
+        let client = GeminiClient::with_api_key("test-key".to_string())
+            .with_resolved_ip(BASE_IP).unwrap()
+            .with_region("us-central1").unwrap();
+        assert_eq!(client.base_url, format!("https://us-central1-{}/v1beta", REAL_HOST));
+        assert!(GeminiClient::with_api_key("test-key".to_string()).with_resolved_ip("not an ip").is_err());
+
+        let resolver = FixedIpResolver { ip: BASE_IP.parse().unwrap(), fallback: DefaultResolver::default() };
+        let ip: IpAddr = BASE_IP.parse().unwrap();
+        assert_eq!(resolver.addr_for(Some(REAL_HOST), None), Some(SocketAddr::new(ip, 443)));
+        assert_eq!(
+            resolver.addr_for(Some("us-central1-generativelanguage.googleapis.com"), Some(8443)),
+            Some(SocketAddr::new(ip, 8443)),
+        );
+        assert_eq!(resolver.addr_for(Some("example.com"), None), None);
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Filter {
+        field: String,
+        min: Option<f64>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Order {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    #[allow(dead_code)]
+    struct Query {
+        text: String,
+        max_results: Option<u32>,
+        tags: Vec<String>,
+        filter: Filter,
+        order: Order,
+        exact: bool,
+    }
+
+    #[test]
+    fn schema_for_follows_field_types() {
+        let schema = schema_for::<Query>().unwrap();
+        assert_eq!(schema.required, ["text", "tags", "filter", "order", "exact"]);
+        assert_eq!(serde_json::to_value(&schema).unwrap(), serde_json::json!({
+            "type": "object",
+            "properties": {
+                "text": { "type": "string" },
+                "maxResults": { "type": "integer" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "filter": {
+                    "type": "object",
+                    "properties": {
+                        "field": { "type": "string" },
+                        "min": { "type": "number" },
+                    },
+                    "required": ["field"],
+                },
+                "order": { "type": "string", "enum": ["asc", "desc"] },
+                "exact": { "type": "boolean" },
+            },
+            "required": ["text", "tags", "filter", "order", "exact"],
+        }));
+    }
+
+    #[test]
+    fn generate_json_as_sends_schema_and_parses_response() {
+        let transport = FakeTransport::new(&[r#"{"candidates":[{"content":{"role":"model","parts":[
+            {"text":"{\"field\":\"price\",\"min\":null}"}
+        ]},"finishReason":"STOP"}]}"#]);
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+
+        let filter: Filter = client.generate_json_as("make a filter").unwrap();
+        assert_eq!(filter.field, "price");
+        assert!(filter.min.is_none());
+
+        let sent: Value = serde_json::from_str(&requests.lock().unwrap()[0].1).unwrap();
+        let config = &sent["generation_config"];
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert_eq!(config["responseSchema"]["properties"]["min"]["type"], "number");
+        assert_eq!(config["responseSchema"]["required"], serde_json::json!(["field"]));
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct WithMap {
+        counts: HashMap<String, u32>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Shape {
+        Circle(f64),
+        Square { side: f64 },
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct WithShape {
+        shape: Shape,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Node {
+        children: Vec<Node>,
+    }
+
+    #[test]
+    fn schema_for_rejects_types_without_a_fixed_schema() {
+        let err = schema_for::<WithMap>().unwrap_err();
+        assert!(err.to_string().contains("field 'counts'"), "{}", err);
+        let err = schema_for::<WithShape>().unwrap_err();
+        assert!(err.to_string().contains("enum Shape"), "{}", err);
+        let err = schema_for::<Node>().unwrap_err();
+        assert!(err.to_string().contains("recursive"), "{}", err);
+        assert!(schema_for::<u32>().is_err());
+        assert!(schema_for::<Option<Filter>>().is_err());
+    }
+
+    #[test]
+    fn streaming_surfaces_error_responses() {
+        let body = r#"{"error":{"code":400,"message":"Invalid JSON payload","status":"INVALID_ARGUMENT"}}"#;
+        let transport = FakeTransport::new(&[])
+            .with_stream(400, "application/json; charset=UTF-8", body)
+            .with_stream(400, "application/json; charset=UTF-8", body)
+            .with_stream(200, "application/json", body);
+        let requests = transport.requests.clone();
+        let client = client_with(transport);
+
+        match client.generate_streamed("hi").unwrap_err() {
+            GeminiError::HttpStatus { code, message, status, .. } => {
+                assert_eq!(code, 400);
+                assert_eq!(message, "Invalid JSON payload");
+                assert_eq!(status.as_deref(), Some("INVALID_ARGUMENT"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(requests.lock().unwrap()[0].0.ends_with(":streamGenerateContent?alt=sse"));
+        assert!(matches!(client.stream_with_functions("hi").err(), Some(GeminiError::HttpStatus { code: 400, .. })));
+        // 2xx でも SSE でなければエラーのJSONを読む
+        let err = client.generate_streamed("hi").unwrap_err();
+        assert!(matches!(&err, GeminiError::ApiError(message) if message == "Invalid JSON payload"), "{:?}", err);
+    }
+
+    #[test]
+    fn streaming_through_transport_merges_events() {
+        let sse = concat!(
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"Hel\"}]}}]}\n\n",
+            "data: {\"candidates\":[{\"content\":{\"parts\":[{\"text\":\"lo\"}]},\"finishReason\":\"STOP\"}]}\n\n",
+        );
+        let client = client_with(FakeTransport::new(&[]).with_stream(200, "text/event-stream", sse));
+
+        let response = client.generate_streamed("hi").unwrap();
+        assert_eq!(response.candidates[0].text(), "Hello");
+    }
+
+    #[test]
+    fn stream_idle_timeout_default_needs_no_thread_on_wasm() {
+        let client = GeminiClient::with_api_key("test-key".to_string());
+        if cfg!(target_family = "wasm") {
+            assert_eq!(client.stream_idle_timeout, None);
+        } else {
+            assert_eq!(client.stream_idle_timeout, Some(Duration::from_secs(60)));
+        }
+    }
+
+    #[test]
+    fn without_functions_sends_no_tools() {
+        let ok = r#"{"candidates":[{"content":{"role":"model","parts":[{"text":"ok"}]},"finishReason":"STOP"}]}"#;
+        let transport = FakeTransport::new(&[ok]);
+        let requests = transport.requests.clone();
+        let mut client = client_with(transport).with_code_execution(true);
+        client.register_function(declaration("weather"), |_| Ok(Value::Null));
+
+        client.without_functions().run_agent("summarize", 1).unwrap();
+        let sent: Value = serde_json::from_str(&requests.lock().unwrap()[0].1).unwrap();
+        assert!(sent.get("tools").is_none(), "{}", sent);
+    }
+
+    #[test]
+    fn into_turn_keeps_request_ready_content() {
+        let response: GenerateContentResponse = serde_json::from_str(r#"{"candidates":[{"content":{"parts":[
+            {"text":"Let me check."},
+            {"functionCall":{"name":"weather","args":{"city":"Tokyo"}},"thoughtSignature":"sig"}
+        ]},"finishReason":"STOP"}]}"#).unwrap();
+
+        let turn = response.into_turn();
+        assert_eq!(turn.text.as_deref(), Some("Let me check."));
+        assert_eq!(turn.calls[0].name, "weather");
+        assert_eq!(serde_json::to_value(&turn.content).unwrap(), serde_json::json!({
+            "role": "model",
+            "parts": [
+                { "text": "Let me check." },
+                { "functionCall": { "name": "weather", "args": { "city": "Tokyo" } }, "thoughtSignature": "sig" },
+            ],
+        }));
+    }
+}